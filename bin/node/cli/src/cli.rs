@@ -84,4 +84,10 @@ pub enum Subcommand {
 
 	/// Revert the chain to a previous state.
 	Revert(sc_cli::RevertCmd),
+
+	/// Report what a state pruning mode would discard.
+	PruningPlan(sc_cli::PruningPlanCmd),
+
+	/// Print the state pruning mode stored in the database.
+	PruningMode(sc_cli::PruningModeCmd),
 }
@@ -0,0 +1,159 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2022 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::{
+	error,
+	params::{DatabaseParams, SharedParams},
+	CliConfiguration,
+};
+use sc_service::{DatabaseSource, PruningMode};
+use sp_runtime::traits::Block as BlockT;
+use structopt::StructOpt;
+
+/// The `pruning-mode` command used to print the state pruning mode stored in the database.
+#[derive(Debug, StructOpt, Clone)]
+pub struct PruningModeCmd {
+	#[allow(missing_docs)]
+	#[structopt(flatten)]
+	pub shared_params: SharedParams,
+
+	#[allow(missing_docs)]
+	#[structopt(flatten)]
+	pub database_params: DatabaseParams,
+}
+
+impl PruningModeCmd {
+	/// Run the pruning-mode command
+	pub fn run<B: BlockT>(&self, database_config: DatabaseSource) -> error::Result<()> {
+		print!("{}", describe_stored::<B>(&database_config)?);
+		Ok(())
+	}
+}
+
+fn describe_stored<B: BlockT>(database_config: &DatabaseSource) -> error::Result<String> {
+	let mode = sc_service::stored_pruning_mode::<B>(database_config)?;
+	Ok(describe(mode.as_ref()))
+}
+
+fn describe(mode: Option<&PruningMode>) -> String {
+	match mode {
+		None => "Stored pruning mode: none, the database has no genesis state yet\n".into(),
+		Some(mode @ PruningMode::Constrained(constraints)) => format!(
+			"Stored pruning mode: {}\nMaximum blocks: {}\nMaximum memory: {}\nMaximum age: {}\n",
+			mode.as_str(),
+			describe_limit(constraints.max_blocks.map(|n| n as usize)),
+			describe_limit(constraints.max_mem),
+			match constraints.max_age {
+				Some(max_age) => format!("{}h", max_age.as_secs() / 3600),
+				None => "unspecified".into(),
+			},
+		),
+		Some(mode) => format!("Stored pruning mode: {}\n", mode.as_str()),
+	}
+}
+
+fn describe_limit(limit: Option<usize>) -> String {
+	match limit {
+		Some(limit) => limit.to_string(),
+		None => "unspecified".into(),
+	}
+}
+
+impl CliConfiguration for PruningModeCmd {
+	fn shared_params(&self) -> &SharedParams {
+		&self.shared_params
+	}
+
+	fn database_params(&self) -> Option<&DatabaseParams> {
+		Some(&self.database_params)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use sc_service::Constraints;
+	use sp_core::H256;
+	use sp_database::{Database, MemDb, Transaction};
+	use sp_runtime::testing::{Block as RawBlock, ExtrinsicWrapper};
+	use std::{sync::Arc, time::Duration};
+	use tempfile::TempDir;
+
+	type Block = RawBlock<ExtrinsicWrapper<u64>>;
+
+	#[test]
+	fn prints_the_stored_mode() {
+		let db = Arc::new(MemDb::default());
+		let source = DatabaseSource::Custom(db.clone());
+		assert_eq!(
+			describe_stored::<Block>(&source).unwrap(),
+			"Stored pruning mode: none, the database has no genesis state yet\n",
+		);
+
+		// the state meta-data column, and the key the pruning mode is stored at
+		let mut transaction = Transaction::<H256>::new();
+		transaction.set(2, b"mode", b"archive_canonical");
+		db.commit(transaction).unwrap();
+		assert_eq!(
+			describe_stored::<Block>(&source).unwrap(),
+			"Stored pruning mode: archive-canonical\n",
+		);
+	}
+
+	#[test]
+	fn does_not_create_a_database() {
+		let dir = TempDir::new().unwrap();
+		let path = dir.path().join("db");
+		let sources = [
+			DatabaseSource::RocksDb { path: path.clone(), cache_size: 128 },
+			DatabaseSource::ParityDb { path: path.clone() },
+			DatabaseSource::Auto {
+				paritydb_path: path.join("paritydb"),
+				rocksdb_path: path.join("rocksdb"),
+				cache_size: 128,
+			},
+		];
+		for source in sources {
+			assert_eq!(
+				describe_stored::<Block>(&source).unwrap(),
+				"Stored pruning mode: none, the database has no genesis state yet\n",
+			);
+			assert!(!path.exists());
+		}
+	}
+
+	#[test]
+	fn describes_stored_mode() {
+		assert_eq!(
+			describe(None),
+			"Stored pruning mode: none, the database has no genesis state yet\n",
+		);
+		assert_eq!(describe(Some(&PruningMode::ArchiveAll)), "Stored pruning mode: archive\n");
+
+		let mode = PruningMode::Constrained(Constraints {
+			max_blocks: Some(256),
+			max_mem: None,
+			max_age: Some(Duration::from_secs(72 * 3600)),
+		});
+		assert_eq!(
+			describe(Some(&mode)),
+			"Stored pruning mode: constrained\nMaximum blocks: 256\nMaximum memory: unspecified\n\
+			Maximum age: 72h\n",
+		);
+	}
+}
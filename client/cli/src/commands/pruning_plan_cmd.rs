@@ -0,0 +1,161 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2022 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::{error, params::PruningParams};
+use sc_service::{PruningMode, PruningPlan, Role};
+use std::ops::Range;
+use structopt::StructOpt;
+
+/// The `pruning-plan` command used to report what a state pruning mode would discard.
+///
+/// Nothing is read from or written to the database: the heights of the chain are given on the
+/// command line.
+#[derive(Debug, StructOpt)]
+pub struct PruningPlanCmd {
+	/// Number of the best block.
+	#[structopt(long, value_name = "NUMBER")]
+	pub best: u64,
+
+	/// Number of the last finalized block.
+	#[structopt(long, value_name = "NUMBER")]
+	pub finalized: u64,
+
+	/// Plan the pruning of a validator node.
+	#[structopt(long)]
+	pub validator: bool,
+
+	/// Plan pruning the state of a validator node, see the option of the same name of the node.
+	#[structopt(long = "unsafe-pruning")]
+	pub unsafe_pruning: bool,
+
+	#[allow(missing_docs)]
+	#[structopt(flatten)]
+	pub pruning_params: PruningParams,
+}
+
+impl PruningPlanCmd {
+	/// Run the pruning-plan command
+	pub fn run(&self) -> error::Result<()> {
+		let (mode, plan) = self.plan()?;
+		println!("Pruning mode: {}", mode);
+		print!("{}", describe(&plan));
+		Ok(())
+	}
+
+	/// The requested pruning mode, and what it would discard.
+	fn plan(&self) -> error::Result<(PruningMode, PruningPlan)> {
+		let role = if self.validator { Role::Authority } else { Role::Full };
+		let mode = self.pruning_params.state_pruning(self.unsafe_pruning, &role)?;
+		let plan = mode.plan(self.best, self.finalized);
+		Ok((mode, plan))
+	}
+}
+
+fn describe(plan: &PruningPlan) -> String {
+	format!(
+		"Discarded canonical states: {}\nKept states: {}\nDiscarded non-canonical states: {}\n",
+		describe_blocks(&plan.discarded),
+		describe_blocks(&plan.kept),
+		if plan.discards_non_canonical { "yes" } else { "no" },
+	)
+}
+
+fn describe_blocks(blocks: &Range<u64>) -> String {
+	if blocks.is_empty() {
+		"none".into()
+	} else {
+		format!("blocks #{} to #{}", blocks.start, blocks.end - 1)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn plans_pruning_validators_with_unsafe_pruning() {
+		let plan = |args: &[&str]| {
+			PruningPlanCmd::from_iter(
+				["pruning-plan", "--best", "1000", "--finalized", "990", "--validator"]
+					.iter()
+					.chain(args),
+			)
+			.plan()
+		};
+
+		let (mode, plan_of_archive) = plan(&[]).unwrap();
+		assert_eq!(mode, PruningMode::ArchiveAll);
+		assert_eq!(
+			plan_of_archive,
+			PruningPlan { discarded: 0..0, kept: 0..1001, discards_non_canonical: false },
+		);
+
+		assert!(plan(&["--pruning", "256"]).is_err());
+		let (mode, plan_of_pruned) = plan(&["--pruning", "256", "--unsafe-pruning"]).unwrap();
+		assert_eq!(mode, PruningMode::keep_blocks(256));
+		assert_eq!(
+			plan_of_pruned,
+			PruningPlan { discarded: 0..735, kept: 735..1001, discards_non_canonical: true },
+		);
+	}
+
+	#[test]
+	fn parses_the_highest_block_number() {
+		let cmd = PruningPlanCmd::from_iter(&[
+			"pruning-plan",
+			"--best",
+			"18446744073709551615",
+			"--finalized",
+			"18446744073709551615",
+		]);
+		assert_eq!(cmd.best, u64::MAX);
+		assert_eq!(cmd.finalized, u64::MAX);
+
+		// the ranges end at the highest block number, which they leave out
+		let (mode, plan) = cmd.plan().unwrap();
+		assert_eq!(mode, PruningMode::keep_blocks(256));
+		assert_eq!(
+			plan,
+			PruningPlan {
+				discarded: 0..u64::MAX - 256,
+				kept: u64::MAX - 256..u64::MAX,
+				discards_non_canonical: true,
+			},
+		);
+		assert_eq!(
+			describe(&plan),
+			"Discarded canonical states: blocks #0 to #18446744073709551358\n\
+			Kept states: blocks #18446744073709551359 to #18446744073709551614\n\
+			Discarded non-canonical states: yes\n",
+		);
+	}
+
+	#[test]
+	fn describes_plan() {
+		let plan = PruningPlan { discarded: 0..735, kept: 735..1001, discards_non_canonical: true };
+		assert_eq!(
+			describe(&plan),
+			"Discarded canonical states: blocks #0 to #734\n\
+			Kept states: blocks #735 to #1000\n\
+			Discarded non-canonical states: yes\n",
+		);
+
+		let plan = PruningPlan { discarded: 0..0, kept: 0..1001, discards_non_canonical: false };
+		assert!(describe(&plan).starts_with("Discarded canonical states: none\n"));
+	}
+}
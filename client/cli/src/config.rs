@@ -19,8 +19,9 @@
 //! Configuration trait for a CLI based on substrate
 
 use crate::{
-	arg_enums::Database, error::Result, DatabaseParams, ImportParams, KeystoreParams,
-	NetworkParams, NodeKeyParams, OffchainWorkerParams, PruningParams, SharedParams, SubstrateCli,
+	arg_enums::Database, error::Result, params::has_unknown_constraints, DatabaseParams,
+	ImportParams, KeystoreParams, NetworkParams, NodeKeyParams, OffchainWorkerParams,
+	PruningParams, SharedParams, SubstrateCli,
 };
 use log::warn;
 use names::{Generator, Name};
@@ -28,7 +29,7 @@ use sc_client_api::execution_extensions::ExecutionStrategies;
 use sc_service::{
 	config::{
 		BasePath, Configuration, DatabaseSource, KeystoreConfig, NetworkConfiguration,
-		NodeKeyConfig, OffchainWorkerConfig, PrometheusConfig, PruningMode, Role, RpcMethods,
+		NodeKeyConfig, OffchainWorkerConfig, PrometheusConfig, Role, RpcMethods, StatePruning,
 		TelemetryEndpoints, TransactionPoolOptions, WasmExecutionMethod,
 	},
 	ChainSpec, KeepBlocks, TracingReceiver, TransactionStorageMode,
@@ -248,14 +249,28 @@ pub trait CliConfiguration<DCV: DefaultConfigurationValues = ()>: Sized {
 		Ok(Default::default())
 	}
 
-	/// Get the state pruning mode.
+	/// Get how to choose the state pruning mode, given the one stored in the database.
 	///
-	/// By default this is retrieved from `PruningMode` if it is available. Otherwise its
-	/// `PruningMode::default()`.
-	fn state_pruning(&self, unsafe_pruning: bool, role: &Role) -> Result<PruningMode> {
-		self.pruning_params()
-			.map(|x| x.state_pruning(unsafe_pruning, role))
-			.unwrap_or_else(|| Ok(Default::default()))
+	/// By default this is chosen by `PruningParams::effective_pruning_mode` if they are
+	/// available, once the requested mode, if any, is checked to be valid. Otherwise it is the
+	/// stored mode, or `PruningMode::default()` if none is stored or its constraints are unknown.
+	fn state_pruning(&self, unsafe_pruning: bool, role: &Role) -> Result<StatePruning> {
+		let params = match self.pruning_params() {
+			Some(params) => params.clone(),
+			None =>
+				return Ok(StatePruning::choose(|stored| {
+					Ok(stored.filter(|stored| !has_unknown_constraints(stored)).unwrap_or_default())
+				})),
+		};
+		if params.pruning.is_some() {
+			params.state_pruning(unsafe_pruning, role)?;
+		}
+		let role = role.clone();
+		Ok(StatePruning::choose(move |stored| {
+			params
+				.effective_pruning_mode(unsafe_pruning, &role, stored)
+				.map_err(|e| e.to_string())
+		}))
 	}
 
 	/// Get the block pruning mode.
@@ -496,6 +511,14 @@ pub trait CliConfiguration<DCV: DefaultConfigurationValues = ()>: Sized {
 		let runtime_cache_size = self.runtime_cache_size()?;
 
 		let unsafe_pruning = self.import_params().map(|p| p.unsafe_pruning).unwrap_or(false);
+		let state_pruning = self.state_pruning(unsafe_pruning, &role)?;
+		let keep_blocks = self.keep_blocks()?;
+		// the state pruning mode is only known once the database is opened
+		let state_pruning = StatePruning::choose(move |stored| {
+			let mode = state_pruning.resolve(stored)?;
+			PruningParams::check_consistency(&mode, &keep_blocks).map_err(|e| e.to_string())?;
+			Ok(mode)
+		});
 
 		Ok(Configuration {
 			impl_name: C::impl_name(),
@@ -517,8 +540,8 @@ pub trait CliConfiguration<DCV: DefaultConfigurationValues = ()>: Sized {
 			database: self.database_config(&config_dir, database_cache_size, database, &role)?,
 			state_cache_size: self.state_cache_size()?,
 			state_cache_child_ratio: self.state_cache_child_ratio()?,
-			state_pruning: self.state_pruning(unsafe_pruning, &role)?,
-			keep_blocks: self.keep_blocks()?,
+			state_pruning,
+			keep_blocks,
 			transaction_storage: self.database_transaction_storage()?,
 			wasm_method: self.wasm_method()?,
 			wasm_runtime_overrides: self.wasm_runtime_overrides(),
@@ -662,3 +685,107 @@ pub fn generate_node_name() -> String {
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use sc_service::{ChainType, GenericChainSpec, NoExtension, PruningMode};
+	use structopt::StructOpt;
+	use tempfile::TempDir;
+
+	struct Cli;
+
+	impl SubstrateCli for Cli {
+		fn impl_name() -> String {
+			"test".into()
+		}
+
+		fn impl_version() -> String {
+			"2.0".into()
+		}
+
+		fn description() -> String {
+			"test".into()
+		}
+
+		fn support_url() -> String {
+			"test.test".into()
+		}
+
+		fn copyright_start_year() -> i32 {
+			2022
+		}
+
+		fn author() -> String {
+			"test".into()
+		}
+
+		fn native_runtime_version(_: &Box<dyn ChainSpec>) -> &'static sp_version::RuntimeVersion {
+			unimplemented!("Not required in tests")
+		}
+
+		fn load_spec(&self, _: &str) -> std::result::Result<Box<dyn ChainSpec>, String> {
+			Ok(Box::new(GenericChainSpec::from_genesis(
+				"test",
+				"test_id",
+				ChainType::Development,
+				|| unimplemented!("Not required in tests"),
+				Vec::new(),
+				None,
+				None,
+				None,
+				None,
+				NoExtension::None,
+			)))
+		}
+	}
+
+	struct Cmd {
+		shared_params: SharedParams,
+		pruning_params: PruningParams,
+	}
+
+	impl CliConfiguration for Cmd {
+		fn shared_params(&self) -> &SharedParams {
+			&self.shared_params
+		}
+
+		fn pruning_params(&self) -> Option<&PruningParams> {
+			Some(&self.pruning_params)
+		}
+	}
+
+	fn configure(pruning: &[&str]) -> Result<Configuration> {
+		let path = TempDir::new().unwrap();
+		let base_path = format!("{}", path.path().display());
+		let cmd = Cmd {
+			shared_params: SharedParams::from_iter(&["test", "-d", &base_path]),
+			pruning_params: PruningParams::from_iter(
+				std::iter::once("test").chain(pruning.iter().copied()),
+			),
+		};
+		let runtime = tokio::runtime::Runtime::new().unwrap();
+		cmd.create_configuration(&Cli, runtime.handle().clone())
+	}
+
+	#[test]
+	fn chooses_the_pruning_mode_given_the_stored_one() {
+		let pruning = configure(&[]).unwrap().state_pruning;
+		assert_eq!(pruning.resolve(None), Ok(PruningMode::default()));
+		// the stored mode is kept over the default of the role
+		assert_eq!(pruning.resolve(Some(PruningMode::ArchiveAll)), Ok(PruningMode::ArchiveAll));
+		let pruning = configure(&["--pruning", "archive"]).unwrap().state_pruning;
+		assert_eq!(pruning.resolve(Some(PruningMode::ArchiveAll)), Ok(PruningMode::ArchiveAll));
+
+		// a differing mode is refused once the stored one is known
+		let pruning = configure(&["--pruning", "16"]).unwrap().state_pruning;
+		assert!(pruning.resolve(Some(PruningMode::ArchiveAll)).is_err());
+		// as is keeping fewer blocks than the stored mode keeps the state of
+		let pruning = configure(&["--keep-blocks", "512"]).unwrap().state_pruning;
+		assert_eq!(pruning.resolve(None), Ok(PruningMode::default()));
+		assert!(pruning.resolve(Some(PruningMode::keep_blocks(1024))).is_err());
+
+		// while invalid parameters are refused right away
+		assert!(configure(&["--pruning", "0"]).is_err());
+	}
+}
@@ -17,58 +17,611 @@
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
 use crate::error;
-use sc_service::{KeepBlocks, PruningMode, Role};
+use log::warn;
+use sc_service::{Constraints, KeepBlocks, PruningMode, Role};
 use structopt::StructOpt;
 
+/// The accepted values of `--pruning`, shared by its help text and its errors.
+///
+/// These are parsed by `PruningMode::from_str`, which also names the stored modes.
+macro_rules! state_pruning_values {
+	() => {
+		"'archive', 'archive-canonical' or a non-zero number of blocks to keep, optionally \
+		written 'constrained:<COUNT>', and optionally followed by ':<HOURS>h' to also discard \
+		the states older than that"
+	};
+}
+
+/// The accepted values of `--keep-blocks`, shared by its help text and its errors.
+macro_rules! block_pruning_values {
+	() => {
+		"'archive' or a non-zero number of finalized blocks to keep"
+	};
+}
+
 /// Parameters to define the pruning mode
-#[derive(Debug, StructOpt, Clone)]
+///
+/// Besides parsing them from the command line, they can be built with [`Default::default`] and
+/// the `with_*` setters, which take the values of the matching flags.
+#[derive(Debug, StructOpt, Clone, Default)]
 pub struct PruningParams {
-	/// Specify the state pruning mode, a number of blocks to keep or 'archive'.
-	///
-	/// Default is to keep all block states if the node is running as a
-	/// validator (i.e. 'archive'), otherwise state is only kept for the last
-	/// 256 blocks.
-	#[structopt(long = "pruning", value_name = "PRUNING_MODE")]
+	/// Specify the state pruning mode.
+	#[structopt(
+		long = "pruning",
+		value_name = "PRUNING_MODE",
+		long_help = concat!(
+			"Specify the state pruning mode: ",
+			state_pruning_values!(),
+			".\n\n",
+			"'archive' keeps the state of all the blocks, while 'archive-canonical' only keeps ",
+			"the state of the canonical (i.e. finalized) ones.\n\n",
+			"With both a number of blocks and of hours, e.g. '256:72h', the stricter bound wins: ",
+			"at most 256 blocks are kept, none of them older than 72 hours.\n\n",
+			"Keeping 0 blocks is refused, as is keeping 0 finalized blocks with `--keep-blocks`.\n\n",
+			"Default is to keep all block states if the node is running as a validator ",
+			"(i.e. 'archive'), otherwise state is only kept for the last 256 blocks.",
+		)
+	)]
 	pub pruning: Option<String>,
-	/// Specify the number of finalized blocks to keep in the database.
+	/// Specify the memory budget of the state pruning window, in bytes.
 	///
-	/// Default is to keep all blocks.
-	#[structopt(long, value_name = "COUNT")]
-	pub keep_blocks: Option<u32>,
+	/// Blocks are pruned past the number of blocks to keep when the window exceeds it, as
+	/// estimated from the state it is left to delete. Only applies when pruning is enabled.
+	#[structopt(long, value_name = "BYTES")]
+	pub pruning_max_mem: Option<usize>,
+	/// Specify the number of finalized blocks to keep in the database.
+	#[structopt(
+		long,
+		alias = "blocks-pruning",
+		value_name = "COUNT",
+		long_help = concat!(
+			"Specify the block pruning mode: ",
+			block_pruning_values!(),
+			".\n\n",
+			"When the state is pruned, at least as many blocks as the state is kept for must be ",
+			"kept.\n\n",
+			"Default is to keep all blocks (i.e. 'archive').",
+		),
+		parse(try_from_str = parse_keep_blocks)
+	)]
+	pub keep_blocks: Option<KeepBlocks>,
+}
+
+/// Parse the block pruning setting, either a number of blocks or 'archive'.
+fn parse_keep_blocks(s: &str) -> std::result::Result<KeepBlocks, String> {
+	match s {
+		"archive" => Ok(KeepBlocks::All),
+		n => n.parse().map(KeepBlocks::Some).map_err(|_| {
+			format!("Invalid block pruning value '{}', expected {}", n, block_pruning_values!())
+		}),
+	}
 }
 
 impl PruningParams {
+	/// Set the state pruning mode, as given to `--pruning`.
+	pub fn with_pruning(mut self, pruning: impl Into<String>) -> Self {
+		self.pruning = Some(pruning.into());
+		self
+	}
+
+	/// Set the memory budget of the state pruning window, as given to `--pruning-max-mem`.
+	pub fn with_pruning_max_mem(mut self, max_mem: usize) -> Self {
+		self.pruning_max_mem = Some(max_mem);
+		self
+	}
+
+	/// Set the number of finalized blocks to keep, as given to `--keep-blocks`.
+	pub fn with_keep_blocks(mut self, keep_blocks: KeepBlocks) -> Self {
+		self.keep_blocks = Some(keep_blocks);
+		self
+	}
+
 	/// Get the pruning value from the parameters
 	pub fn state_pruning(&self, unsafe_pruning: bool, role: &Role) -> error::Result<PruningMode> {
 		// by default we disable pruning if the node is an authority (i.e.
 		// `ArchiveAll`), otherwise we keep state for the last 256 blocks. if the
 		// node is an authority and pruning is enabled explicitly, then we error
 		// unless `unsafe_pruning` is set.
-		Ok(match &self.pruning {
-			Some(ref s) if s == "archive" => PruningMode::ArchiveAll,
-			None if role.is_authority() => PruningMode::ArchiveAll,
-			None => PruningMode::default(),
-			Some(s) => {
-				if role.is_authority() && !unsafe_pruning {
-					return Err(error::Error::Input(
-						"Validators should run with state pruning disabled (i.e. archive). \
-						You can ignore this check with `--unsafe-pruning`."
-							.to_string(),
-					))
-				}
-
-				PruningMode::keep_blocks(s.parse().map_err(|_| {
-					error::Error::Input("Invalid pruning mode specified".to_string())
-				})?)
+		let mode = match &self.pruning {
+			None => default_state_pruning(role),
+			Some(s) => match s.parse() {
+				Ok(mode) if mode.is_archive() => mode,
+				Ok(_) if role.is_authority() && !unsafe_pruning =>
+					return Err(unsafe_pruning_error()),
+				Ok(mode @ PruningMode::Constrained(Constraints { max_blocks: Some(1..), .. })) =>
+					mode,
+				Ok(PruningMode::Constrained(Constraints { max_blocks: Some(0), .. })) =>
+					return Err(error::Error::Input(format!(
+						"Invalid pruning mode '{}': the state of at least the last finalized \
+						block must be kept, expected {}",
+						s,
+						state_pruning_values!(),
+					))),
+				_ =>
+					return Err(error::Error::Input(format!(
+						"Invalid pruning mode '{}', expected {}",
+						s,
+						state_pruning_values!(),
+					))),
 			},
+		};
+		self.with_max_mem(mode)
+	}
+
+	/// Apply the memory budget of the parameters, if any, to the pruning `mode`.
+	fn with_max_mem(&self, mode: PruningMode) -> error::Result<PruningMode> {
+		Ok(match (mode, self.pruning_max_mem) {
+			(mode, None) => mode,
+			(PruningMode::Constrained(constraints), max_mem @ Some(_)) =>
+				PruningMode::Constrained(Constraints { max_mem, ..constraints }),
+			(_, Some(_)) =>
+				return Err(error::Error::Input(
+					"`--pruning-max-mem` requires state pruning to be enabled".to_string(),
+				)),
 		})
 	}
 
+	/// Get the pruning mode to open a database with, given the mode it was created with, if any.
+	///
+	/// In order of precedence, the mode is:
+	/// - the one given by the parameters, which must keep as many blocks as the stored one;
+	/// - the stored one, which validators only keep pruned with `unsafe_pruning`, with the memory
+	/// budget of the parameters if any;
+	/// - the default of the `role`, see [`Self::state_pruning`], which must match the stored one
+	/// if its constraints were not recorded.
+	///
+	/// Warns when the stored mode is kept while the default of the `role` differs, and when the
+	/// mode discards history the stored one kept.
+	pub fn effective_pruning_mode(
+		&self,
+		unsafe_pruning: bool,
+		role: &Role,
+		stored: Option<PruningMode>,
+	) -> error::Result<PruningMode> {
+		let (mode, warnings) = self.decide_pruning_mode(unsafe_pruning, role, stored)?;
+		for warning in warnings {
+			warn!("{}", warning);
+		}
+		Ok(mode)
+	}
+
+	/// Decide the pruning mode as [`Self::effective_pruning_mode`] does, returning the warnings
+	/// about it instead of logging them.
+	fn decide_pruning_mode(
+		&self,
+		unsafe_pruning: bool,
+		role: &Role,
+		stored: Option<PruningMode>,
+	) -> error::Result<(PruningMode, Vec<String>)> {
+		match stored {
+			Some(stored) if self.pruning.is_none() && !has_unknown_constraints(&stored) => {
+				if role.is_authority() && !unsafe_pruning && !stored.is_archive() {
+					return Err(unsafe_pruning_error())
+				}
+				let warnings = role_change_warning(role, &stored).into_iter().collect();
+				Ok((self.with_max_mem(stored)?, warnings))
+			},
+			stored => {
+				let mode = self.state_pruning(unsafe_pruning, role)?;
+				match stored {
+					Some(stored) if !is_compatible(&stored, &mode) =>
+						Err(error::Error::Input(format!(
+							"The database was created with the {:?} state pruning mode, \
+							which differs from {:?}{}",
+							stored,
+							mode,
+							match shrink_warning(&stored, &mode) {
+								Some(shrink) => format!(". {}", shrink),
+								None => String::new(),
+							},
+						))),
+					stored => {
+						let warnings =
+							stored.and_then(|stored| shrink_warning(&stored, &mode)).into_iter();
+						Ok((mode, warnings.collect()))
+					},
+				}
+			},
+		}
+	}
+
 	/// Get the block pruning value from the parameters
 	pub fn keep_blocks(&self) -> error::Result<KeepBlocks> {
 		Ok(match self.keep_blocks {
-			Some(n) => KeepBlocks::Some(n),
+			Some(KeepBlocks::Some(0)) =>
+				return Err(error::Error::Input(format!(
+					"Invalid block pruning value '0', expected {}",
+					block_pruning_values!(),
+				))),
+			Some(keep_blocks) => keep_blocks,
 			None => KeepBlocks::All,
 		})
 	}
+
+	/// Check that the finalized blocks are kept at least as long as the state pruning window
+	/// keeps their state, which would otherwise outlive the blocks it belongs to.
+	pub fn check_consistency(
+		state_pruning: &PruningMode,
+		keep_blocks: &KeepBlocks,
+	) -> error::Result<()> {
+		match (state_pruning, keep_blocks) {
+			(
+				PruningMode::Constrained(Constraints { max_blocks: Some(window), .. }),
+				KeepBlocks::Some(keep_blocks),
+			) if keep_blocks < window => Err(error::Error::Input(format!(
+				"Keeping the {} last finalized blocks is inconsistent with keeping the state of the \
+				{} last ones, as the state of the pruned blocks would be kept. Keep at least {} \
+				blocks or prune the state more.",
+				keep_blocks, window, window,
+			))),
+			_ => Ok(()),
+		}
+	}
+}
+
+/// The state pruning mode of the `role` when none is given: archive for the validators, the
+/// default pruning window otherwise.
+fn default_state_pruning(role: &Role) -> PruningMode {
+	if role.is_authority() {
+		PruningMode::ArchiveAll
+	} else {
+		PruningMode::default()
+	}
+}
+
+/// The error refusing to prune the state of a validator without `--unsafe-pruning`.
+fn unsafe_pruning_error() -> error::Error {
+	error::Error::Input(
+		"Validators should run with state pruning disabled (i.e. archive). \
+		You can ignore this check with `--unsafe-pruning`."
+			.to_string(),
+	)
+}
+
+/// A warning about the `stored` mode being kept while the default of the `role` would archive
+/// a pruned database or prune an archive one, which usually means the role changed since the
+/// database was created.
+fn role_change_warning(role: &Role, stored: &PruningMode) -> Option<String> {
+	let default = default_state_pruning(role);
+	if stored.is_archive() == default.is_archive() {
+		return None
+	}
+	Some(format!(
+		"The database was created with the '{}' state pruning mode, while the default for {} is \
+		'{}'. Was the role of the node changed? The stored mode is kept, pass `--pruning` to \
+		choose the mode explicitly.",
+		stored,
+		if role.is_authority() { "validators" } else { "non-validator nodes" },
+		default,
+	))
+}
+
+/// A warning about the history kept by the `stored` mode which `mode` discards, if any.
+fn shrink_warning(stored: &PruningMode, mode: &PruningMode) -> Option<String> {
+	match (stored, mode) {
+		(stored, PruningMode::Constrained(Constraints { max_blocks: Some(max_blocks), .. }))
+			if has_unknown_constraints(stored) =>
+			Some(format!(
+				"The state pruning window of the database was not recorded, it is now set to the \
+				{} last blocks: if more were kept, the state of the older ones will be discarded.",
+				max_blocks,
+			)),
+		(stored, mode) => stored.shrinks_to(mode).map(|shrink| {
+			format!(
+				"Switching from the '{}' to the '{}' state pruning mode: {}.",
+				stored, mode, shrink
+			)
+		}),
+	}
+}
+
+/// Whether the `stored` mode was recorded before its constraints were.
+pub(crate) fn has_unknown_constraints(stored: &PruningMode) -> bool {
+	matches!(
+		stored,
+		PruningMode::Constrained(Constraints { max_blocks: None, max_mem: None, max_age: None })
+	)
+}
+
+/// Whether a database created with the `stored` pruning mode can be opened with `mode`.
+///
+/// The memory budget and the maximum age only tune how the pruning window is kept, and may
+/// change between runs.
+fn is_compatible(stored: &PruningMode, mode: &PruningMode) -> bool {
+	match (stored, mode) {
+		(stored, PruningMode::Constrained(_)) if has_unknown_constraints(stored) => true,
+		(PruningMode::Constrained(stored), PruningMode::Constrained(mode)) =>
+			stored.max_blocks == mode.max_blocks,
+		(stored, mode) => stored == mode,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Parameters with the given `--pruning`, if any.
+	fn params(pruning: Option<&str>) -> PruningParams {
+		match pruning {
+			Some(pruning) => PruningParams::default().with_pruning(pruning),
+			None => PruningParams::default(),
+		}
+	}
+
+	fn state_pruning(pruning: &str) -> error::Result<PruningMode> {
+		params(Some(pruning)).state_pruning(false, &Role::Full)
+	}
+
+	#[test]
+	fn parses_state_pruning_modes() {
+		assert_eq!(state_pruning("archive").unwrap(), PruningMode::ArchiveAll);
+		assert_eq!(state_pruning("archive-canonical").unwrap(), PruningMode::ArchiveCanonical);
+		assert_eq!(state_pruning("archive_canonical").unwrap(), PruningMode::ArchiveCanonical);
+		assert_eq!(state_pruning("1024").unwrap(), PruningMode::keep_blocks(1024));
+		assert_eq!(state_pruning("constrained:1024").unwrap(), PruningMode::keep_blocks(1024));
+		assert!(state_pruning("constrained").is_err());
+		assert_eq!(
+			state_pruning("256:72h").unwrap(),
+			PruningMode::Constrained(Constraints {
+				max_blocks: Some(256),
+				max_mem: None,
+				max_age: Some(std::time::Duration::from_secs(72 * 3600)),
+			}),
+		);
+		assert!(state_pruning("0:72h").is_err());
+		assert!(state_pruning("256:72").is_err());
+		assert!(state_pruning("everything").is_err());
+
+		for mode in [PruningMode::ArchiveAll, PruningMode::ArchiveCanonical, PruningMode::default()]
+		{
+			assert_eq!(state_pruning(&mode.to_string()).unwrap(), mode);
+		}
+	}
+
+	#[test]
+	fn rejects_keeping_zero_blocks() {
+		for pruning in ["0", "constrained:0", "0:72h"] {
+			match state_pruning(pruning) {
+				Err(error::Error::Input(message)) =>
+					assert!(message.contains("at least the last finalized block"), "{}", message),
+				_ => panic!("keeping 0 blocks must be refused"),
+			}
+		}
+		assert_eq!(state_pruning("1").unwrap(), PruningMode::keep_blocks(1));
+
+		let keep_blocks = |keep_blocks| PruningParams::default().with_keep_blocks(keep_blocks);
+		assert!(keep_blocks(KeepBlocks::Some(0)).keep_blocks().is_err());
+		assert!(matches!(keep_blocks(KeepBlocks::Some(1)).keep_blocks(), Ok(KeepBlocks::Some(1))));
+		assert!(matches!(PruningParams::default().keep_blocks(), Ok(KeepBlocks::All)));
+	}
+
+	#[test]
+	fn parses_block_pruning_aliases() {
+		let keep_blocks = |args: &[&str]| {
+			PruningParams::from_iter(std::iter::once("test").chain(args.iter().copied()))
+				.keep_blocks()
+				.unwrap()
+		};
+
+		for flag in ["--keep-blocks", "--blocks-pruning"] {
+			assert!(matches!(keep_blocks(&[flag, "16"]), KeepBlocks::Some(16)));
+			assert!(matches!(keep_blocks(&[flag, "archive"]), KeepBlocks::All));
+		}
+		assert!(matches!(keep_blocks(&[]), KeepBlocks::All));
+		assert!(PruningParams::from_iter_safe(&["test", "--blocks-pruning", "all"]).is_err());
+	}
+
+	#[test]
+	fn invalid_values_are_reported_with_the_valid_ones() {
+		match state_pruning("everything") {
+			Err(error::Error::Input(message)) => {
+				assert!(message.contains("'everything'"));
+				assert!(message.contains(state_pruning_values!()));
+			},
+			_ => panic!("invalid state pruning must be reported"),
+		}
+
+		let error = parse_keep_blocks("everything").unwrap_err();
+		assert!(error.contains("'everything'"));
+		assert!(error.contains(block_pruning_values!()));
+
+		let error = PruningParams::default()
+			.with_keep_blocks(KeepBlocks::Some(0))
+			.keep_blocks()
+			.unwrap_err();
+		assert!(error.to_string().contains(block_pruning_values!()));
+	}
+
+	#[test]
+	fn applies_pruning_memory_budget() {
+		let params = |pruning| params(pruning).with_pruning_max_mem(1024);
+		let constrained = |max_blocks| {
+			PruningMode::Constrained(Constraints {
+				max_blocks: Some(max_blocks),
+				max_mem: Some(1024),
+				max_age: None,
+			})
+		};
+
+		assert_eq!(params(Some("16")).state_pruning(false, &Role::Full).unwrap(), constrained(16));
+		assert_eq!(params(None).state_pruning(false, &Role::Full).unwrap(), constrained(256));
+		assert!(params(Some("archive")).state_pruning(false, &Role::Full).is_err());
+	}
+
+	#[test]
+	fn builds_params_programmatically() {
+		let params = PruningParams::default();
+		assert_eq!(params.state_pruning(false, &Role::Full).unwrap(), PruningMode::default());
+		assert!(matches!(params.keep_blocks(), Ok(KeepBlocks::All)));
+
+		let params = PruningParams::default()
+			.with_pruning("16")
+			.with_pruning_max_mem(1024)
+			.with_keep_blocks(KeepBlocks::Some(32));
+		assert_eq!(
+			params.state_pruning(false, &Role::Full).unwrap(),
+			PruningMode::Constrained(Constraints {
+				max_blocks: Some(16),
+				max_mem: Some(1024),
+				max_age: None,
+			}),
+		);
+		assert!(matches!(params.keep_blocks(), Ok(KeepBlocks::Some(32))));
+
+		let parsed = PruningParams::from_iter(&[
+			"test",
+			"--pruning",
+			"16",
+			"--pruning-max-mem",
+			"1024",
+			"--keep-blocks",
+			"32",
+		]);
+		assert_eq!(format!("{:?}", parsed), format!("{:?}", params));
+	}
+
+	#[test]
+	fn checks_block_and_state_pruning_consistency() {
+		let check = |state_pruning, keep_blocks| {
+			PruningParams::check_consistency(&state_pruning, &keep_blocks)
+		};
+
+		assert!(check(PruningMode::keep_blocks(256), KeepBlocks::Some(256)).is_ok());
+		assert!(check(PruningMode::keep_blocks(16), KeepBlocks::Some(256)).is_ok());
+		assert!(check(PruningMode::keep_blocks(256), KeepBlocks::All).is_ok());
+		assert!(check(PruningMode::ArchiveAll, KeepBlocks::Some(16)).is_ok());
+
+		match check(PruningMode::keep_blocks(256), KeepBlocks::Some(16)) {
+			Err(error::Error::Input(message)) => {
+				assert!(message.contains("16 last finalized blocks"), "{}", message);
+				assert!(message.contains("256 last ones"), "{}", message);
+			},
+			_ => panic!("pruning blocks within the state pruning window must be refused"),
+		}
+	}
+
+	#[test]
+	fn warns_about_role_changes() {
+		let pruned = PruningMode::default();
+		// authority to full node
+		let warning = role_change_warning(&Role::Full, &PruningMode::ArchiveAll).unwrap();
+		assert!(warning.contains("'archive'"));
+		assert!(warning.contains(&format!("'{}'", pruned)));
+		assert!(role_change_warning(&Role::Full, &pruned).is_none());
+		// full node to authority
+		let warning = role_change_warning(&Role::Authority, &pruned).unwrap();
+		assert!(warning.contains(&format!("'{}'", pruned)));
+		assert!(warning.contains("validators"));
+		assert!(role_change_warning(&Role::Authority, &PruningMode::ArchiveAll).is_none());
+	}
+
+	#[test]
+	fn warns_about_discarded_history() {
+		let decide = |pruning, role, stored: PruningMode| {
+			params(pruning).decide_pruning_mode(false, &role, Some(stored))
+		};
+		let refusal = |decided: error::Result<_>| match decided {
+			Err(error::Error::Input(message)) => message,
+			_ => panic!("the mode must be refused"),
+		};
+
+		// shrinking, refused with what would be discarded
+		let message = refusal(decide(Some("16"), Role::Full, PruningMode::ArchiveAll));
+		assert!(message.contains("discarded, but for the latest 16"), "{}", message);
+		let message = refusal(decide(Some("16"), Role::Full, PruningMode::keep_blocks(256)));
+		assert!(message.contains("the 240 oldest blocks"), "{}", message);
+		// shrinking a window which was not recorded
+		let (mode, warnings) =
+			decide(None, Role::Full, PruningMode::Constrained(Constraints::default())).unwrap();
+		assert_eq!(mode, PruningMode::keep_blocks(256));
+		assert_eq!(warnings.len(), 1);
+		assert!(warnings[0].contains("not recorded"), "{}", warnings[0]);
+		assert!(warnings[0].contains("256 last blocks"), "{}", warnings[0]);
+
+		// widening, refused without a warning
+		let message = refusal(decide(Some("256"), Role::Full, PruningMode::keep_blocks(16)));
+		assert!(!message.contains("discarded"), "{}", message);
+		let message = refusal(decide(Some("archive"), Role::Full, PruningMode::keep_blocks(16)));
+		assert!(!message.contains("discarded"), "{}", message);
+		// no change
+		let decided = decide(Some("16"), Role::Full, PruningMode::keep_blocks(16)).unwrap();
+		assert_eq!(decided, (PruningMode::keep_blocks(16), vec![]));
+		let decided = decide(Some("archive"), Role::Full, PruningMode::ArchiveAll).unwrap();
+		assert_eq!(decided, (PruningMode::ArchiveAll, vec![]));
+		let decided = decide(None, Role::Authority, PruningMode::ArchiveAll).unwrap();
+		assert_eq!(decided, (PruningMode::ArchiveAll, vec![]));
+	}
+
+	#[test]
+	fn warns_about_role_changes_when_keeping_the_stored_mode() {
+		let decide = |role, stored: PruningMode| {
+			PruningParams::default()
+				.decide_pruning_mode(true, &role, Some(stored))
+				.unwrap()
+		};
+
+		let (mode, warnings) = decide(Role::Full, PruningMode::ArchiveAll);
+		assert_eq!(mode, PruningMode::ArchiveAll);
+		assert_eq!(warnings, vec![role_change_warning(&Role::Full, &mode).unwrap()]);
+		let (mode, warnings) = decide(Role::Authority, PruningMode::keep_blocks(16));
+		assert_eq!(mode, PruningMode::keep_blocks(16));
+		assert_eq!(warnings, vec![role_change_warning(&Role::Authority, &mode).unwrap()]);
+	}
+
+	#[test]
+	fn effective_pruning_mode_precedence() {
+		let legacy = PruningMode::Constrained(Constraints::default());
+		let archive = Some(PruningMode::ArchiveAll);
+		let keep = |n| Some(PruningMode::keep_blocks(n));
+		// (role, `--pruning`, stored mode, effective mode or `None` if refused)
+		let cases = [
+			(Role::Full, None, None, keep(256)),
+			(Role::Full, None, archive.clone(), archive.clone()),
+			(Role::Full, None, keep(16), keep(16)),
+			(Role::Full, None, Some(legacy.clone()), keep(256)),
+			(Role::Full, Some("archive"), None, archive.clone()),
+			(Role::Full, Some("archive"), archive.clone(), archive.clone()),
+			(Role::Full, Some("archive"), keep(16), None),
+			(Role::Full, Some("16"), None, keep(16)),
+			(Role::Full, Some("16"), archive.clone(), None),
+			(Role::Full, Some("16"), keep(16), keep(16)),
+			(Role::Full, Some("16"), keep(256), None),
+			(Role::Full, Some("16"), Some(legacy.clone()), keep(16)),
+			(Role::Authority, None, None, archive.clone()),
+			(Role::Authority, None, archive.clone(), archive.clone()),
+			(Role::Authority, None, keep(16), None),
+			(Role::Authority, None, Some(legacy.clone()), None),
+			(Role::Authority, Some("archive"), keep(16), None),
+			(Role::Authority, Some("16"), None, None),
+		];
+
+		for (role, pruning, stored, expected) in cases {
+			let effective =
+				params(pruning).effective_pruning_mode(false, &role, stored.clone()).ok();
+			assert_eq!(effective, expected, "{:?} with {:?} on {:?}", role, pruning, stored);
+		}
+	}
+
+	#[test]
+	fn memory_budget_and_maximum_age_may_change() {
+		let budget = |max_mem| {
+			PruningMode::Constrained(Constraints { max_blocks: Some(16), max_mem, max_age: None })
+		};
+		let effective = |params: PruningParams, stored| {
+			params.effective_pruning_mode(false, &Role::Full, Some(stored)).unwrap()
+		};
+
+		// the budget is applied to the stored mode, or replaces its own
+		let params = PruningParams::default().with_pruning_max_mem(2048);
+		assert_eq!(effective(params.clone(), PruningMode::keep_blocks(16)), budget(Some(2048)));
+		assert_eq!(effective(params, budget(Some(1024))), budget(Some(2048)));
+		// and dropped when not given
+		let params = PruningParams::default().with_pruning("16");
+		assert_eq!(effective(params, budget(Some(1024))), PruningMode::keep_blocks(16));
+		let params = PruningParams::default().with_pruning("16:72h");
+		assert!(matches!(
+			effective(params, PruningMode::keep_blocks(16)),
+			PruningMode::Constrained(Constraints { max_blocks: Some(16), max_age: Some(_), .. }),
+		));
+	}
 }
@@ -91,7 +91,7 @@ use sp_state_machine::{
 use sp_trie::{prefixed_key, MemoryDB, PrefixedMemoryDB};
 
 // Re-export the Database trait so that one can pass an implementation of it.
-pub use sc_state_db::PruningMode;
+pub use sc_state_db::{Constraints, PruningMode, PruningPlan, PruningShrink};
 pub use sp_database::Database;
 
 #[cfg(any(feature = "with-kvdb-rocksdb", test))]
@@ -289,13 +289,62 @@ pub struct DatabaseSettings {
 	/// Ratio of cache size dedicated to child tries.
 	pub state_cache_child_ratio: Option<(usize, usize)>,
 	/// State pruning mode.
-	pub state_pruning: PruningMode,
+	pub state_pruning: StatePruning,
 	/// Where to find the database.
 	pub source: DatabaseSource,
 	/// Block pruning mode.
 	pub keep_blocks: KeepBlocks,
 	/// Block body/Transaction storage scheme.
 	pub transaction_storage: TransactionStorageMode,
+	/// Version of the node, recorded along with the state pruning mode of the database.
+	pub node_version: String,
+}
+
+/// State pruning settings: either a mode, or how to choose it.
+#[derive(Clone)]
+pub enum StatePruning {
+	/// Use this mode, which must match the one the database was created with.
+	Mode(PruningMode),
+	/// Choose the mode given the one the database was created with, if any.
+	Choose(Arc<dyn Fn(Option<PruningMode>) -> Result<PruningMode, String> + Send + Sync>),
+}
+
+impl StatePruning {
+	/// Choose the mode with a function of the one the database was created with, if any.
+	pub fn choose(
+		f: impl Fn(Option<PruningMode>) -> Result<PruningMode, String> + Send + Sync + 'static,
+	) -> Self {
+		StatePruning::Choose(Arc::new(f))
+	}
+
+	/// Get the mode to open a database created with the `stored` mode with, if any.
+	pub fn resolve(&self, stored: Option<PruningMode>) -> Result<PruningMode, String> {
+		match self {
+			StatePruning::Mode(mode) => Ok(mode.clone()),
+			StatePruning::Choose(choose) => choose(stored),
+		}
+	}
+}
+
+impl Default for StatePruning {
+	fn default() -> Self {
+		StatePruning::Mode(Default::default())
+	}
+}
+
+impl From<PruningMode> for StatePruning {
+	fn from(mode: PruningMode) -> Self {
+		StatePruning::Mode(mode)
+	}
+}
+
+impl std::fmt::Debug for StatePruning {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			StatePruning::Mode(mode) => f.debug_tuple("Mode").field(mode).finish(),
+			StatePruning::Choose(_) => write!(f, "Choose"),
+		}
+	}
 }
 
 /// Block pruning settings.
@@ -426,6 +475,21 @@ impl<'a> sc_state_db::MetaDb for StateMetaDb<'a> {
 	}
 }
 
+/// Read the state pruning mode stored in the database at `source`.
+///
+/// Returns `None` if no mode is stored yet, i.e. the genesis block was not imported, or if there
+/// is no database at `source`, which is then left as is.
+pub fn stored_pruning_mode<Block: BlockT>(
+	source: &DatabaseSource,
+) -> ClientResult<Option<PruningMode>> {
+	let db = match utils::open_existing_database::<Block>(source, DatabaseType::Full)? {
+		Some(db) => db,
+		None => return Ok(None),
+	};
+	StateDb::<Block::Hash, Vec<u8>>::stored_pruning_mode(&StateMetaDb(&*db))
+		.map_err(sp_blockchain::Error::from_state_db)
+}
+
 struct MetaUpdate<Block: BlockT> {
 	pub hash: Block::Hash,
 	pub number: NumberFor<Block>,
@@ -1042,10 +1106,11 @@ impl<Block: BlockT> Backend<Block> {
 		let db_setting = DatabaseSettings {
 			state_cache_size: 16777216,
 			state_cache_child_ratio: Some((50, 100)),
-			state_pruning: PruningMode::keep_blocks(keep_blocks),
+			state_pruning: PruningMode::keep_blocks(keep_blocks).into(),
 			source: DatabaseSource::Custom(db),
 			keep_blocks: KeepBlocks::Some(keep_blocks),
 			transaction_storage,
+			node_version: "test".into(),
 		};
 
 		Self::new(db_setting, canonicalization_delay).expect("failed to create test-db")
@@ -1056,12 +1121,24 @@ impl<Block: BlockT> Backend<Block> {
 		canonicalization_delay: u64,
 		config: &DatabaseSettings,
 	) -> ClientResult<Self> {
-		let is_archive_pruning = config.state_pruning.is_archive();
 		let blockchain = BlockchainDb::new(db.clone(), config.transaction_storage.clone())?;
 		let map_e = |e: sc_state_db::Error<io::Error>| sp_blockchain::Error::from_state_db(e);
+		if let Some(commit) =
+			StateDb::<Block::Hash, Vec<u8>>::migrate_meta(&StateMetaDb(&*db)).map_err(map_e)?
+		{
+			let mut transaction = Transaction::new();
+			apply_state_commit(&mut transaction, commit);
+			db.commit(transaction)?;
+		}
+		let stored = StateDb::<Block::Hash, Vec<u8>>::stored_pruning_mode(&StateMetaDb(&*db))
+			.map_err(map_e)?;
+		let state_pruning =
+			config.state_pruning.resolve(stored).map_err(sp_blockchain::Error::Backend)?;
+		let is_archive_pruning = state_pruning.is_archive();
 		let state_db: StateDb<_, _> = StateDb::new(
-			config.state_pruning.clone(),
+			state_pruning,
 			!db.supports_ref_counting(),
+			&config.node_version,
 			&StateMetaDb(&*db),
 		)
 		.map_err(map_e)?;
@@ -2376,10 +2453,11 @@ pub(crate) mod tests {
 			DatabaseSettings {
 				state_cache_size: 16777216,
 				state_cache_child_ratio: Some((50, 100)),
-				state_pruning: PruningMode::keep_blocks(1),
+				state_pruning: PruningMode::keep_blocks(1).into(),
 				source: DatabaseSource::Custom(backing),
 				keep_blocks: KeepBlocks::All,
 				transaction_storage: TransactionStorageMode::BlockBody,
+				node_version: "test".into(),
 			},
 			0,
 		)
@@ -2390,6 +2468,46 @@ pub(crate) mod tests {
 		}
 	}
 
+	#[test]
+	fn reads_stored_pruning_mode() {
+		let backend = Backend::<Block>::new_test(4, 0);
+		let source = DatabaseSource::Custom(backend.storage.db.clone());
+		assert_eq!(stored_pruning_mode::<Block>(&source).unwrap(), None);
+
+		insert_header(&backend, 0, Default::default(), None, Default::default());
+		assert_eq!(
+			stored_pruning_mode::<Block>(&source).unwrap(),
+			Some(PruningMode::keep_blocks(4)),
+		);
+		let meta = StateMetaDb(&*backend.storage.db);
+		let writer = StateDb::<H256, Vec<u8>>::stored_pruning_mode_writer(&meta).unwrap();
+		assert_eq!(writer.as_deref(), Some("test"));
+	}
+
+	#[test]
+	fn chooses_the_pruning_mode_given_the_stored_one() {
+		let backend = Backend::<Block>::new_test(4, 0);
+		insert_header(&backend, 0, Default::default(), None, Default::default());
+		let settings = |state_pruning| DatabaseSettings {
+			state_cache_size: 16777216,
+			state_cache_child_ratio: Some((50, 100)),
+			state_pruning,
+			source: DatabaseSource::Custom(backend.storage.db.clone()),
+			keep_blocks: KeepBlocks::All,
+			transaction_storage: TransactionStorageMode::BlockBody,
+			node_version: "test".into(),
+		};
+
+		let keep_stored =
+			StatePruning::choose(|stored| stored.ok_or_else(|| "no stored mode".into()));
+		let reopened = Backend::<Block>::new(settings(keep_stored), 0).unwrap();
+		assert!(!reopened.is_archive);
+		let refuse = StatePruning::choose(|_| Err("refused".into()));
+		assert!(Backend::<Block>::new(settings(refuse), 0).is_err());
+		let archive = StatePruning::choose(|_| Ok(PruningMode::ArchiveAll));
+		assert!(Backend::<Block>::new(settings(archive), 0).is_err());
+	}
+
 	#[test]
 	fn set_state_data() {
 		set_state_data_inner(StateVersion::V0);
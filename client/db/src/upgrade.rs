@@ -191,10 +191,11 @@ mod tests {
 			&DatabaseSettings {
 				state_cache_size: 0,
 				state_cache_child_ratio: None,
-				state_pruning: PruningMode::ArchiveAll,
+				state_pruning: PruningMode::ArchiveAll.into(),
 				source: DatabaseSource::RocksDb { path: db_path.to_owned(), cache_size: 128 },
 				keep_blocks: KeepBlocks::All,
 				transaction_storage: TransactionStorageMode::BlockBody,
+				node_version: "test".into(),
 			},
 			db_type,
 		)
@@ -196,6 +196,30 @@ pub fn open_database<Block: BlockT>(
 	open_database_at::<Block>(&config.source, db_type)
 }
 
+/// Opens the database at `source` the same way as [`open_database`], unless there is none.
+///
+/// Returns `None` if there is no database at `source`, which is then left as is.
+pub(crate) fn open_existing_database<Block: BlockT>(
+	source: &DatabaseSource,
+	db_type: DatabaseType,
+) -> sp_blockchain::Result<Option<Arc<dyn Database<DbHash>>>> {
+	// A database in the layout preceding the type specific subdirectories is moved first, as
+	// it would otherwise be missed.
+	maybe_migrate_to_type_subdir::<Block>(source, db_type).map_err(|e| {
+		sp_blockchain::Error::Backend(format!("Error in migration to role subdirectory: {}", e))
+	})?;
+
+	let exists = match source {
+		DatabaseSource::Auto { paritydb_path, rocksdb_path, .. } =>
+			paritydb_path.exists() || rocksdb_path.exists(),
+		source => source.path().map_or(true, Path::exists),
+	};
+	if !exists {
+		return Ok(None)
+	}
+	open_database_at::<Block>(source, db_type).map(Some)
+}
+
 fn open_database_at<Block: BlockT>(
 	source: &DatabaseSource,
 	db_type: DatabaseType,
@@ -686,10 +710,11 @@ mod tests {
 		DatabaseSettings {
 			state_cache_size: 0,
 			state_cache_child_ratio: None,
-			state_pruning: PruningMode::ArchiveAll,
+			state_pruning: PruningMode::ArchiveAll.into(),
 			source,
 			keep_blocks: KeepBlocks::All,
 			transaction_storage: TransactionStorageMode::BlockBody,
+			node_version: "test".into(),
 		}
 	}
 
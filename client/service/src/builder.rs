@@ -267,6 +267,7 @@ where
 			source: config.database.clone(),
 			keep_blocks: config.keep_blocks.clone(),
 			transaction_storage: config.transaction_storage.clone(),
+			node_version: config.impl_version.clone(),
 		};
 
 		let backend = new_db_backend(db_config)?;
@@ -57,14 +57,15 @@ pub use self::{
 	error::Error,
 };
 pub use config::{
-	BasePath, Configuration, DatabaseSource, KeepBlocks, PruningMode, Role, RpcMethods, TaskType,
-	TransactionStorageMode,
+	BasePath, Configuration, Constraints, DatabaseSource, KeepBlocks, PruningMode, PruningPlan,
+	PruningShrink, Role, RpcMethods, StatePruning, TaskType, TransactionStorageMode,
 };
 pub use sc_chain_spec::{
 	ChainSpec, ChainType, Extension as ChainSpecExtension, GenericChainSpec, NoExtension,
 	Properties, RuntimeGenesis,
 };
 use sc_client_api::{blockchain::HeaderBackend, BlockchainEvents};
+pub use sc_client_db::stored_pruning_mode;
 pub use sc_consensus::ImportQueue;
 pub use sc_executor::NativeExecutionDispatch;
 #[doc(hidden)]
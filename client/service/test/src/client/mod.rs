@@ -1057,10 +1057,11 @@ fn doesnt_import_blocks_that_revert_finality() {
 			DatabaseSettings {
 				state_cache_size: 1 << 20,
 				state_cache_child_ratio: None,
-				state_pruning: PruningMode::ArchiveAll,
+				state_pruning: PruningMode::ArchiveAll.into(),
 				keep_blocks: KeepBlocks::All,
 				transaction_storage: TransactionStorageMode::BlockBody,
 				source: DatabaseSource::RocksDb { path: tmp.path().into(), cache_size: 1024 },
+				node_version: "test".into(),
 			},
 			u64::MAX,
 		)
@@ -1268,10 +1269,11 @@ fn returns_status_for_pruned_blocks() {
 			DatabaseSettings {
 				state_cache_size: 1 << 20,
 				state_cache_child_ratio: None,
-				state_pruning: PruningMode::keep_blocks(1),
+				state_pruning: PruningMode::keep_blocks(1).into(),
 				keep_blocks: KeepBlocks::All,
 				transaction_storage: TransactionStorageMode::BlockBody,
 				source: DatabaseSource::RocksDb { path: tmp.path().into(), cache_size: 1024 },
+				node_version: "test".into(),
 			},
 			u64::MAX,
 		)
@@ -46,8 +46,8 @@ mod pruning;
 #[cfg(test)]
 mod test;
 
-use codec::Codec;
-use log::trace;
+use codec::{Codec, Decode, Encode};
+use log::{trace, warn};
 use noncanonical::NonCanonicalOverlay;
 use parity_util_mem::{malloc_size, MallocSizeOf};
 use parking_lot::RwLock;
@@ -56,12 +56,25 @@ use sc_client_api::{MemorySize, StateDbMemoryInfo};
 use std::{
 	collections::{hash_map::Entry, HashMap},
 	fmt,
+	ops::Range,
+	str::FromStr,
+	time::Duration,
 };
 
 const PRUNING_MODE: &[u8] = b"mode";
+const PRUNING_MODE_ARG: &[u8] = b"mode_arg";
+const PRUNING_META_VERSION: &[u8] = b"mode_version";
+const PRUNING_MODE_WRITER_VERSION: &[u8] = b"mode_writer_version";
 const PRUNING_MODE_ARCHIVE: &[u8] = b"archive";
 const PRUNING_MODE_ARCHIVE_CANON: &[u8] = b"archive_canonical";
 const PRUNING_MODE_CONSTRAINED: &[u8] = b"constrained";
+/// Prefix of the checksummed `mode_arg` entries. The unchecksummed ones start with the encoding
+/// of an `Option`, i.e. with 0 or 1, and can't be mistaken for it.
+const PRUNING_MODE_ARG_MAGIC: &[u8] = b"pma";
+/// Version of the layout of the pruning mode meta-data written by this version.
+const CURRENT_PRUNING_META_VERSION: u32 = 2;
+/// First version of the layout of the pruning mode meta-data to checksum the `mode_arg` entry.
+const CHECKSUMMED_PRUNING_META_VERSION: u32 = 2;
 
 /// Database value type.
 pub type DBValue = Vec<u8>;
@@ -128,8 +141,19 @@ pub enum Error<E: fmt::Debug> {
 	InvalidParent,
 	/// Invalid pruning mode specified. Contains expected mode.
 	InvalidPruningMode(String),
+	/// The pruning mode stored in the database is not known to this version. Contains the
+	/// stored mode.
+	UnknownPruningMode(Vec<u8>),
+	/// Invalid pruning constraints specified. Contains the constraints stored in the database.
+	InvalidPruningConstraints(Constraints),
+	/// The pruning mode meta-data was written in a layout newer than this version supports.
+	/// Contains the stored layout version.
+	UnsupportedPruningMetaVersion(u32),
 	/// Too many unfinalized sibling blocks inserted.
 	TooManySiblingBlocks,
+	/// The database can not be switched between the given pruning modes. Contains the stored
+	/// and the requested modes.
+	UnsupportedPruningMigration(PruningMode, PruningMode),
 }
 
 /// Pinning error type.
@@ -153,7 +177,27 @@ impl<E: fmt::Debug> fmt::Debug for Error<E> {
 			Error::InvalidBlockNumber => write!(f, "Trying to insert block with invalid number"),
 			Error::InvalidParent => write!(f, "Trying to insert block with unknown parent"),
 			Error::InvalidPruningMode(e) => write!(f, "Expected pruning mode: {}", e),
+			Error::UnknownPruningMode(e) =>
+				write!(f, "Unknown pruning mode in the database: {}", String::from_utf8_lossy(e)),
+			Error::InvalidPruningConstraints(c) =>
+				write!(f, "Expected pruning constraints: {:?}", c),
+			Error::UnsupportedPruningMetaVersion(v) => write!(
+				f,
+				"Unsupported pruning meta-data version: {}, expected at most {}",
+				v, CURRENT_PRUNING_META_VERSION,
+			),
 			Error::TooManySiblingBlocks => write!(f, "Too many sibling blocks inserted"),
+			Error::UnsupportedPruningMigration(from, to) => write!(
+				f,
+				"Switching from {:?} to {:?} pruning is not supported: {}",
+				from,
+				to,
+				if *to == PruningMode::ArchiveAll {
+					"the discarded non-canonical states are gone"
+				} else {
+					"the state of the pruned blocks is gone"
+				},
+			),
 		}
 	}
 }
@@ -184,6 +228,28 @@ pub struct Constraints {
 	pub max_blocks: Option<u32>,
 	/// Maximum memory in the pruning overlay.
 	pub max_mem: Option<usize>,
+	/// Maximum age of the canonical states, counted from their canonicalization. Older states
+	/// are pruned even within `max_blocks`.
+	pub max_age: Option<Duration>,
+}
+
+impl Constraints {
+	/// Whether a pruning window of `window_size` blocks using `mem_used` bytes, the oldest of
+	/// which was canonicalized `oldest_age` ago, must be pruned further.
+	///
+	/// Each of the bounds is enforced on its own, so the stricter one wins.
+	fn exceeded_by(&self, window_size: u64, mem_used: usize, oldest_age: Option<Duration>) -> bool {
+		let over_blocks = window_size > self.max_blocks.unwrap_or(0) as u64;
+		let over_budget = match self.max_mem {
+			Some(max_mem) => mem_used > max_mem,
+			None => false,
+		};
+		let over_age = match (self.max_age, oldest_age) {
+			(Some(max_age), Some(age)) => age >= max_age,
+			_ => false,
+		};
+		over_blocks || over_budget || over_age
+	}
 }
 
 /// Pruning mode.
@@ -200,7 +266,7 @@ pub enum PruningMode {
 impl PruningMode {
 	/// Create a mode that keeps given number of blocks.
 	pub fn keep_blocks(n: u32) -> PruningMode {
-		PruningMode::Constrained(Constraints { max_blocks: Some(n), max_mem: None })
+		PruningMode::Constrained(Constraints { max_blocks: Some(n), max_mem: None, max_age: None })
 	}
 
 	/// Is this an archive (either ArchiveAll or ArchiveCanonical) pruning mode?
@@ -211,6 +277,54 @@ impl PruningMode {
 		}
 	}
 
+	/// Describe what the mode discards once the chain reaches the `best` block, with the blocks
+	/// up to `finalized` canonicalized. This does not look at any database.
+	///
+	/// A memory budget or a maximum age may discard more canonical blocks than planned, never
+	/// less. The ranges of the plan end at the highest block number, which they cannot include.
+	pub fn plan(&self, best: u64, finalized: u64) -> PruningPlan {
+		let finalized = finalized.min(best);
+		let discarded_end = match self {
+			PruningMode::ArchiveAll | PruningMode::ArchiveCanonical => 0,
+			PruningMode::Constrained(constraints) => finalized
+				.saturating_add(1)
+				.saturating_sub(constraints.max_blocks.unwrap_or(0) as u64),
+		};
+		PruningPlan {
+			discarded: 0..discarded_end,
+			kept: discarded_end..best.saturating_add(1),
+			discards_non_canonical: !matches!(self, PruningMode::ArchiveAll),
+		}
+	}
+
+	/// Describe the history which stops being kept when switching from this mode to the `to` one,
+	/// if any. Changes of the memory budget or of the maximum age are not accounted for.
+	pub fn shrinks_to(&self, to: &PruningMode) -> Option<PruningShrink> {
+		let keep_blocks = |constraints: &Constraints| constraints.max_blocks.unwrap_or(0);
+		match (self, to) {
+			(PruningMode::ArchiveAll, PruningMode::ArchiveCanonical) =>
+				Some(PruningShrink::NonCanonical),
+			(
+				PruningMode::ArchiveAll | PruningMode::ArchiveCanonical,
+				PruningMode::Constrained(c),
+			) => Some(PruningShrink::Canonical(keep_blocks(c))),
+			(PruningMode::Constrained(from), PruningMode::Constrained(to))
+				if keep_blocks(to) < keep_blocks(from) =>
+				Some(PruningShrink::Window(keep_blocks(from) - keep_blocks(to))),
+			_ => None,
+		}
+	}
+
+	/// Name of the mode, as parsed by [`PruningMode::from_str`]. The constrained mode is named
+	/// the same whatever its constraints, see the [`Display`](fmt::Display) implementation.
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			PruningMode::ArchiveAll => "archive",
+			PruningMode::ArchiveCanonical => "archive-canonical",
+			PruningMode::Constrained(_) => "constrained",
+		}
+	}
+
 	/// Is this an archive (either ArchiveAll or ArchiveCanonical) pruning mode?
 	pub fn id(&self) -> &[u8] {
 		match self {
@@ -221,12 +335,184 @@ impl PruningMode {
 	}
 }
 
+/// What a pruning mode discards, see [`PruningMode::plan`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PruningPlan {
+	/// Numbers of the canonical blocks whose state is discarded.
+	pub discarded: Range<u64>,
+	/// Numbers of the blocks whose state is kept.
+	pub kept: Range<u64>,
+	/// Whether the state of the blocks which do not make it to the canonical chain is
+	/// discarded.
+	pub discards_non_canonical: bool,
+}
+
+/// History which stops being kept by a change of the pruning mode, see
+/// [`PruningMode::shrinks_to`].
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum PruningShrink {
+	/// The state of the blocks which do not make it to the canonical chain is discarded.
+	NonCanonical,
+	/// The state of the canonical blocks is discarded, but for the given number of the latest
+	/// ones.
+	Canonical(u32),
+	/// The pruning window gets shorter by the given number of blocks, whose state gets
+	/// discarded.
+	Window(u32),
+}
+
+impl fmt::Display for PruningShrink {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			PruningShrink::NonCanonical =>
+				write!(f, "the state of the non-canonical blocks will be discarded"),
+			PruningShrink::Canonical(n) => write!(
+				f,
+				"the state of the canonical blocks will be discarded, but for the latest {}",
+				n
+			),
+			PruningShrink::Window(n) => write!(
+				f,
+				"the state of the {} oldest blocks of the pruning window will be discarded",
+				n
+			),
+		}
+	}
+}
+
+/// Is `id` the id of one of the known pruning modes?
+fn is_known_pruning_mode_id(id: &[u8]) -> bool {
+	matches!(id, PRUNING_MODE_ARCHIVE | PRUNING_MODE_ARCHIVE_CANON | PRUNING_MODE_CONSTRAINED)
+}
+
+/// Formats the mode as parsed by [`PruningMode::from_str`], e.g. `constrained:256` or
+/// `constrained:256:72h`. The memory budget is left out.
+impl fmt::Display for PruningMode {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			PruningMode::Constrained(Constraints { max_blocks: Some(n), max_age, .. }) => {
+				write!(f, "{}:{}", self.as_str(), n)?;
+				match max_age {
+					Some(max_age) => write!(f, ":{}h", max_age.as_secs() / 3600),
+					None => Ok(()),
+				}
+			},
+			mode => f.write_str(mode.as_str()),
+		}
+	}
+}
+
+/// Parses `archive`, `archive-canonical` or `constrained`, optionally followed by `:N` to keep
+/// `N` blocks, and then by `:Mh` to keep no state older than `M` hours. A bare `N` or `N:Mh` is
+/// the same as `constrained:N` or `constrained:N:Mh`.
+///
+/// The names the modes are stored under in the meta-data are accepted as well.
+impl FromStr for PruningMode {
+	type Err = String;
+
+	fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+		match s {
+			"archive" => Ok(PruningMode::ArchiveAll),
+			"archive-canonical" | "archive_canonical" => Ok(PruningMode::ArchiveCanonical),
+			"constrained" => Ok(PruningMode::Constrained(Constraints::default())),
+			s => parse_constraints(s.strip_prefix("constrained:").unwrap_or(s))
+				.map(PruningMode::Constrained)
+				.ok_or_else(|| format!("Invalid pruning mode '{}'", s)),
+		}
+	}
+}
+
+/// Parse `N` or `N:Mh`, keeping `N` blocks and no state older than a non-zero `M` hours.
+fn parse_constraints(s: &str) -> Option<Constraints> {
+	let (max_blocks, max_age) = match s.split_once(':') {
+		Some((max_blocks, max_age)) => (max_blocks, Some(max_age)),
+		None => (s, None),
+	};
+	let max_age = match max_age {
+		Some(max_age) => match max_age.strip_suffix('h')?.parse::<u64>().ok()? {
+			0 => return None,
+			hours => Some(Duration::from_secs(hours * 3600)),
+		},
+		None => None,
+	};
+	Some(Constraints { max_blocks: Some(max_blocks.parse().ok()?), max_mem: None, max_age })
+}
+
 impl Default for PruningMode {
 	fn default() -> Self {
 		PruningMode::keep_blocks(256)
 	}
 }
 
+/// Encode the constraints of the `Constrained` pruning mode, as stored in the meta-data.
+///
+/// The encoded constraints are prefixed with [`PRUNING_MODE_ARG_MAGIC`] and their checksum. The
+/// maximum age is stored in seconds.
+fn encode_constraints(constraints: &Constraints) -> Vec<u8> {
+	let encoded = (
+		constraints.max_blocks,
+		constraints.max_mem.map(|m| m as u64),
+		constraints.max_age.map(|a| a.as_secs()),
+	)
+		.encode();
+	[PRUNING_MODE_ARG_MAGIC, &checksum(&encoded), &encoded].concat()
+}
+
+/// CRC32 checksum of the encoded constraints.
+fn checksum(encoded: &[u8]) -> [u8; 4] {
+	let mut hasher = crc32fast::Hasher::new();
+	hasher.update(encoded);
+	hasher.finalize().to_le_bytes()
+}
+
+/// Decode the constraints of the `Constrained` pruning mode, as stored in the meta-data of the
+/// given layout version.
+///
+/// Entries starting with [`PRUNING_MODE_ARG_MAGIC`] must match their checksum. Legacy entries
+/// are written without it and decoded as is, unless the layout version requires it.
+///
+/// Constraints written before the memory budget or the maximum age were stored only hold the
+/// leading fields, and decode with the missing ones unset.
+fn decode_constraints(
+	encoded: &[u8],
+	meta_version: Option<u32>,
+) -> Result<Constraints, codec::Error> {
+	let mut encoded = match encoded.strip_prefix(PRUNING_MODE_ARG_MAGIC) {
+		Some(checked) if checked.len() >= 4 && checked[..4] == checksum(&checked[4..]) =>
+			&checked[4..],
+		Some(_) => return Err("Pruning constraints do not match their checksum".into()),
+		None if meta_version >= Some(CHECKSUMMED_PRUNING_META_VERSION) =>
+			return Err("Pruning constraints are missing their checksum".into()),
+		None => encoded,
+	};
+	let max_blocks = Decode::decode(&mut encoded)?;
+	let max_mem = if encoded.is_empty() {
+		None
+	} else {
+		Option::<u64>::decode(&mut encoded)?.map(|m| m as usize)
+	};
+	let max_age = if encoded.is_empty() {
+		None
+	} else {
+		Option::<u64>::decode(&mut encoded)?.map(Duration::from_secs)
+	};
+	Ok(Constraints { max_blocks, max_mem, max_age })
+}
+
+/// Meta-data entries recording the pruning `mode` of the database, set by the node of the given
+/// version.
+fn pruning_mode_meta(mode: &PruningMode, node_version: &str) -> Vec<(Vec<u8>, Vec<u8>)> {
+	let mut meta = vec![
+		(to_meta_key(PRUNING_META_VERSION, &()), CURRENT_PRUNING_META_VERSION.encode()),
+		(to_meta_key(PRUNING_MODE, &()), mode.id().to_vec()),
+		(to_meta_key(PRUNING_MODE_WRITER_VERSION, &()), node_version.encode()),
+	];
+	if let PruningMode::Constrained(constraints) = mode {
+		meta.push((to_meta_key(PRUNING_MODE_ARG, &()), encode_constraints(constraints)));
+	}
+	meta
+}
+
 fn to_meta_key<S: Codec>(suffix: &[u8], data: &S) -> Vec<u8> {
 	let mut buffer = data.encode();
 	buffer.extend(suffix);
@@ -235,6 +521,11 @@ fn to_meta_key<S: Codec>(suffix: &[u8], data: &S) -> Vec<u8> {
 
 struct StateDbSync<BlockHash: Hash, Key: Hash> {
 	mode: PruningMode,
+	node_version: String,
+	/// Whether the database has no pruning mode recorded yet.
+	mode_unrecorded: bool,
+	/// Whether the pending changes record the pruning mode.
+	mode_recording: bool,
 	non_canonical: NonCanonicalOverlay<BlockHash, Key>,
 	pruning: Option<RefWindow<BlockHash, Key>>,
 	pinned: HashMap<BlockHash, u32>,
@@ -244,36 +535,91 @@ impl<BlockHash: Hash + MallocSizeOf, Key: Hash + MallocSizeOf> StateDbSync<Block
 	fn new<D: MetaDb>(
 		mode: PruningMode,
 		ref_counting: bool,
+		node_version: &str,
 		db: &D,
 	) -> Result<StateDbSync<BlockHash, Key>, Error<D::Error>> {
 		trace!(target: "state-db", "StateDb settings: {:?}. Ref-counting: {}", mode, ref_counting);
 
 		// Check that settings match
 		Self::check_meta(&mode, db)?;
+		let mode_unrecorded =
+			db.get_meta(&to_meta_key(PRUNING_MODE, &())).map_err(Error::Db)?.is_none();
 
 		let non_canonical: NonCanonicalOverlay<BlockHash, Key> = NonCanonicalOverlay::new(db)?;
 		let pruning: Option<RefWindow<BlockHash, Key>> = match mode {
-			PruningMode::Constrained(Constraints { max_mem: Some(_), .. }) => unimplemented!(),
 			PruningMode::Constrained(_) => Some(RefWindow::new(db, ref_counting)?),
 			PruningMode::ArchiveAll | PruningMode::ArchiveCanonical => None,
 		};
 
-		Ok(StateDbSync { mode, non_canonical, pruning, pinned: Default::default() })
+		Ok(StateDbSync {
+			mode,
+			node_version: node_version.into(),
+			mode_unrecorded,
+			mode_recording: false,
+			non_canonical,
+			pruning,
+			pinned: Default::default(),
+		})
 	}
 
 	fn check_meta<D: MetaDb>(mode: &PruningMode, db: &D) -> Result<(), Error<D::Error>> {
+		let version = Self::check_meta_version(db)?;
 		let db_mode = db.get_meta(&to_meta_key(PRUNING_MODE, &())).map_err(Error::Db)?;
 		trace!(target: "state-db",
 			"DB pruning mode: {:?}",
 			db_mode.as_ref().map(|v| std::str::from_utf8(&v))
 		);
 		match &db_mode {
-			Some(v) if v.as_slice() == mode.id() => Ok(()),
+			Some(v) if v.as_slice() == mode.id() => Self::check_meta_constraints(mode, version, db),
+			Some(v) if !is_known_pruning_mode_id(v) => Err(Error::UnknownPruningMode(v.clone())),
 			Some(v) => Err(Error::InvalidPruningMode(String::from_utf8_lossy(v).into())),
 			None => Ok(()),
 		}
 	}
 
+	/// Check the version of the layout of the pruning mode meta-data is supported, and return it.
+	fn check_meta_version<D: MetaDb>(db: &D) -> Result<Option<u32>, Error<D::Error>> {
+		match db.get_meta(&to_meta_key(PRUNING_META_VERSION, &())).map_err(Error::Db)? {
+			Some(encoded) => {
+				let version = u32::decode(&mut encoded.as_slice())?;
+				trace!(target: "state-db", "DB pruning meta-data version: {}", version);
+				if version > CURRENT_PRUNING_META_VERSION {
+					return Err(Error::UnsupportedPruningMetaVersion(version))
+				}
+				Ok(Some(version))
+			},
+			// Legacy layout, written before the version was stored. It only differs from the
+			// first version by the optional `mode_arg`, which `check_meta_constraints` handles.
+			// The first version only differs from the current one by the checksum, which
+			// `decode_constraints` only requires from the current one.
+			None => Ok(None),
+		}
+	}
+
+	fn check_meta_constraints<D: MetaDb>(
+		mode: &PruningMode,
+		meta_version: Option<u32>,
+		db: &D,
+	) -> Result<(), Error<D::Error>> {
+		let constraints = match mode {
+			PruningMode::Constrained(constraints) => constraints,
+			PruningMode::ArchiveAll | PruningMode::ArchiveCanonical => return Ok(()),
+		};
+		// databases created before the constraints were stored are accepted as is
+		let db_constraints =
+			match db.get_meta(&to_meta_key(PRUNING_MODE_ARG, &())).map_err(Error::Db)? {
+				Some(encoded) => decode_constraints(&encoded, meta_version)?,
+				None => return Ok(()),
+			};
+		trace!(target: "state-db", "DB pruning constraints: {:?}", db_constraints);
+		// the memory budget and the maximum age only tune how the window is kept
+		if db_constraints.max_blocks == constraints.max_blocks {
+			Ok(())
+		} else {
+			Err(Error::InvalidPruningConstraints(db_constraints))
+		}
+	}
+
 	fn insert_block<E: fmt::Debug>(
 		&mut self,
 		hash: &BlockHash,
@@ -282,10 +628,7 @@ impl<BlockHash: Hash + MallocSizeOf, Key: Hash + MallocSizeOf> StateDbSync<Block
 		mut changeset: ChangeSet<Key>,
 	) -> Result<CommitSet<Key>, Error<E>> {
 		let mut meta = ChangeSet::default();
-		if number == 0 {
-			// Save pruning mode when writing first block.
-			meta.inserted.push((to_meta_key(PRUNING_MODE, &()), self.mode.id().into()));
-		}
+		meta.inserted.extend(self.unrecorded_mode_meta());
 
 		match self.mode {
 			PruningMode::ArchiveAll => {
@@ -308,6 +651,7 @@ impl<BlockHash: Hash + MallocSizeOf, Key: Hash + MallocSizeOf> StateDbSync<Block
 		hash: &BlockHash,
 	) -> Result<CommitSet<Key>, Error<E>> {
 		let mut commit = CommitSet::default();
+		commit.meta.inserted.extend(self.unrecorded_mode_meta());
 		if self.mode == PruningMode::ArchiveAll {
 			return Ok(commit)
 		}
@@ -325,6 +669,18 @@ impl<BlockHash: Hash + MallocSizeOf, Key: Hash + MallocSizeOf> StateDbSync<Block
 		Ok(commit)
 	}
 
+	/// Meta-data entries recording the pruning mode, if the database has none recorded yet.
+	///
+	/// They are added to the changes of every block until some get applied.
+	fn unrecorded_mode_meta(&mut self) -> Vec<(Vec<u8>, Vec<u8>)> {
+		if self.mode_unrecorded {
+			self.mode_recording = true;
+			pruning_mode_meta(&self.mode, &self.node_version)
+		} else {
+			Vec::new()
+		}
+	}
+
 	fn best_canonical(&self) -> Option<u64> {
 		return self.non_canonical.last_canonicalized_block_number()
 	}
@@ -349,11 +705,17 @@ impl<BlockHash: Hash + MallocSizeOf, Key: Hash + MallocSizeOf> StateDbSync<Block
 			(&mut self.pruning, &self.mode)
 		{
 			loop {
-				if pruning.window_size() <= constraints.max_blocks.unwrap_or(0) as u64 {
+				// keep pruning past `max_blocks` while the window exceeds the memory budget or
+				// holds states older than the maximum age
+				if !constraints.exceeded_by(
+					pruning.window_size(),
+					pruning.mem_used(),
+					pruning.next_age(),
+				) {
 					break
 				}
 
-				if constraints.max_mem.map_or(false, |m| pruning.mem_used() > m) {
+				if pruning.window_size() == 0 {
 					break
 				}
 
@@ -439,6 +801,10 @@ impl<BlockHash: Hash + MallocSizeOf, Key: Hash + MallocSizeOf> StateDbSync<Block
 	}
 
 	fn apply_pending(&mut self) {
+		if self.mode_recording {
+			self.mode_unrecorded = false;
+			self.mode_recording = false;
+		}
 		self.non_canonical.apply_pending();
 		if let Some(pruning) = &mut self.pruning {
 			pruning.apply_pending();
@@ -455,6 +821,7 @@ impl<BlockHash: Hash + MallocSizeOf, Key: Hash + MallocSizeOf> StateDbSync<Block
 	}
 
 	fn revert_pending(&mut self) {
+		self.mode_recording = false;
 		if let Some(pruning) = &mut self.pruning {
 			pruning.revert_pending();
 		}
@@ -478,12 +845,161 @@ pub struct StateDb<BlockHash: Hash, Key: Hash> {
 
 impl<BlockHash: Hash + MallocSizeOf, Key: Hash + MallocSizeOf> StateDb<BlockHash, Key> {
 	/// Creates a new instance. Does not expect any metadata in the database.
+	///
+	/// If the database has no pruning mode recorded, the `mode` is recorded along with the
+	/// changes of the next inserted or canonicalized block. The `node_version` is recorded along
+	/// with it, see [`Self::stored_pruning_mode_writer`].
 	pub fn new<D: MetaDb>(
 		mode: PruningMode,
 		ref_counting: bool,
+		node_version: &str,
 		db: &D,
 	) -> Result<StateDb<BlockHash, Key>, Error<D::Error>> {
-		Ok(StateDb { db: RwLock::new(StateDbSync::new(mode, ref_counting, db)?) })
+		Ok(StateDb { db: RwLock::new(StateDbSync::new(mode, ref_counting, node_version, db)?) })
+	}
+
+	/// Switch the database from the `from` pruning mode to the `to` one.
+	///
+	/// Fails if `from` is not the mode the database was created with, if `to` is `ArchiveAll`
+	/// and `from` is not, as the non-canonical states are gone, or if `from` is `Constrained` and
+	/// `to` is an archive mode, as the state of the pruned blocks is gone. Returns the changes to
+	/// commit, after which the database has to be opened with the `to` mode.
+	///
+	/// `ArchiveAll` databases do not track which blocks are canonical: they start doing so right
+	/// after `last_finalized`, the hash and number of their last finalized block, which must be
+	/// their best block too. The other databases know their last canonicalized block. When
+	/// pruning gets enabled, the pruning window starts at that block: the state of the older ones
+	/// is reported as pruned, as pruning the blocks canonicalized afterwards deletes the nodes
+	/// they share.
+	///
+	/// The `node_version` is recorded along with the `to` mode.
+	pub fn migrate_pruning_mode<D: MetaDb>(
+		db: &D,
+		from: &PruningMode,
+		to: &PruningMode,
+		last_finalized: (BlockHash, u64),
+		node_version: &str,
+	) -> Result<CommitSet<Key>, Error<D::Error>> {
+		StateDbSync::<BlockHash, Key>::check_meta(from, db)?;
+		let supported = match from {
+			PruningMode::ArchiveAll => true,
+			PruningMode::ArchiveCanonical => *to != PruningMode::ArchiveAll,
+			PruningMode::Constrained(_) => !to.is_archive(),
+		};
+		if !supported {
+			return Err(Error::UnsupportedPruningMigration(from.clone(), to.clone()))
+		}
+		if let Some(shrink) = from.shrinks_to(to) {
+			warn!(target: "state-db", "Switching from {:?} to {:?} pruning: {}", from, to, shrink);
+		}
+
+		let mut commit = CommitSet::default();
+		commit.meta.inserted.extend(pruning_mode_meta(to, node_version));
+		if !matches!(to, PruningMode::Constrained(_)) {
+			commit.meta.deleted.push(to_meta_key(PRUNING_MODE_ARG, &()));
+		}
+		let last_canonicalized = if *from == PruningMode::ArchiveAll {
+			if *to != PruningMode::ArchiveAll {
+				let (hash, number) = &last_finalized;
+				NonCanonicalOverlay::<BlockHash, Key>::start_after(hash, *number, &mut commit);
+			}
+			Some(last_finalized)
+		} else {
+			let non_canonical = NonCanonicalOverlay::<BlockHash, Key>::new(db)?;
+			non_canonical
+				.last_canonicalized_hash()
+				.zip(non_canonical.last_canonicalized_block_number())
+		};
+		if from.is_archive() && !to.is_archive() {
+			if let Some((hash, number)) = last_canonicalized {
+				RefWindow::<BlockHash, Key>::start_at(&hash, number, &mut commit);
+			}
+		}
+		Ok(commit)
+	}
+
+	/// Upgrade the pruning mode meta-data of the database to the layout written by this version.
+	///
+	/// Returns the changes to commit, or `None` if the meta-data is already up to date or no
+	/// pruning mode is recorded yet. All of them have to be committed together, so that the
+	/// database is left with either layout. Meant to be called on every startup, before the
+	/// pruning mode is read.
+	///
+	/// The constraints of the databases created before they were recorded stay unknown.
+	pub fn migrate_meta<D: MetaDb>(db: &D) -> Result<Option<CommitSet<Key>>, Error<D::Error>> {
+		let version = StateDbSync::<BlockHash, Key>::check_meta_version(db)?;
+		if version == Some(CURRENT_PRUNING_META_VERSION) {
+			return Ok(None)
+		}
+		let mode = match Self::stored_pruning_mode(db)? {
+			Some(mode) => mode,
+			None => return Ok(None),
+		};
+		trace!(target: "state-db", "Upgrading the pruning meta-data of {:?}", mode);
+
+		let mut commit = CommitSet::default();
+		commit
+			.meta
+			.inserted
+			.push((to_meta_key(PRUNING_META_VERSION, &()), CURRENT_PRUNING_META_VERSION.encode()));
+		let arg_key = to_meta_key(PRUNING_MODE_ARG, &());
+		if let PruningMode::Constrained(constraints) = mode {
+			if db.get_meta(&arg_key).map_err(Error::Db)?.is_some() {
+				commit.meta.inserted.push((arg_key, encode_constraints(&constraints)));
+			}
+		}
+		Ok(Some(commit))
+	}
+
+	/// Fetch the pruning mode recorded in the database, if any.
+	///
+	/// The constraints of the databases created before they were recorded are unknown, and
+	/// reported as unset. Fails if the layout of the meta-data is newer than supported.
+	pub fn stored_pruning_mode<D: MetaDb>(db: &D) -> Result<Option<PruningMode>, Error<D::Error>> {
+		let version = StateDbSync::<BlockHash, Key>::check_meta_version(db)?;
+		let id = match db.get_meta(&to_meta_key(PRUNING_MODE, &())).map_err(Error::Db)? {
+			Some(id) => id,
+			None => return Ok(None),
+		};
+		let mode = match std::str::from_utf8(&id).map(PruningMode::from_str) {
+			Ok(Ok(mode)) if is_known_pruning_mode_id(&id) => mode,
+			_ => return Err(Error::UnknownPruningMode(id)),
+		};
+		Ok(Some(match mode {
+			PruningMode::Constrained(constraints) =>
+				match db.get_meta(&to_meta_key(PRUNING_MODE_ARG, &())).map_err(Error::Db)? {
+					Some(encoded) =>
+						PruningMode::Constrained(decode_constraints(&encoded, version)?),
+					None => PruningMode::Constrained(constraints),
+				},
+			mode => mode,
+		}))
+	}
+
+	/// Fetch the version of the node which last recorded the pruning mode of the database, if
+	/// known. Databases created before it was recorded do not have it.
+	pub fn stored_pruning_mode_writer<D: MetaDb>(
+		db: &D,
+	) -> Result<Option<String>, Error<D::Error>> {
+		db.get_meta(&to_meta_key(PRUNING_MODE_WRITER_VERSION, &()))
+			.map_err(Error::Db)?
+			.map(|encoded| String::decode(&mut encoded.as_slice()))
+			.transpose()
+			.map_err(Into::into)
+	}
+
+	/// Forget the pruning mode recorded in the database.
+	///
+	/// Returns the changes to commit. The database then accepts any pruning mode, and records the
+	/// next one it is opened with, see [`Self::new`].
+	pub fn clear_pruning_mode() -> CommitSet<Key> {
+		let mut commit = CommitSet::default();
+		commit.meta.deleted.extend(
+			[PRUNING_META_VERSION, PRUNING_MODE, PRUNING_MODE_ARG, PRUNING_MODE_WRITER_VERSION]
+				.iter()
+				.map(|suffix| to_meta_key(suffix, &())),
+		);
+		commit
 	}
 
 	/// Add a new non-canonical block.
@@ -571,15 +1087,19 @@ impl<BlockHash: Hash + MallocSizeOf, Key: Hash + MallocSizeOf> StateDb<BlockHash
 #[cfg(test)]
 mod tests {
 	use crate::{
+		checksum, decode_constraints, encode_constraints,
 		test::{make_changeset, make_db, TestDb},
-		Constraints, PruningMode, StateDb,
+		to_meta_key, Constraints, Error, PruningMode, PruningPlan, PruningShrink, StateDb,
+		CHECKSUMMED_PRUNING_META_VERSION, CURRENT_PRUNING_META_VERSION, PRUNING_META_VERSION,
+		PRUNING_MODE, PRUNING_MODE_ARG, PRUNING_MODE_ARG_MAGIC, PRUNING_MODE_WRITER_VERSION,
 	};
+	use codec::Encode;
 	use sp_core::H256;
-	use std::io;
+	use std::{io, time::Duration};
 
 	fn make_test_db(settings: PruningMode) -> (TestDb, StateDb<H256, H256>) {
 		let mut db = make_db(&[91, 921, 922, 93, 94]);
-		let state_db = StateDb::new(settings, false, &db).unwrap();
+		let state_db = StateDb::new(settings, false, "test", &db).unwrap();
 
 		db.commit(
 			&state_db
@@ -661,6 +1181,7 @@ mod tests {
 		let (db, _) = make_test_db(PruningMode::Constrained(Constraints {
 			max_blocks: Some(0),
 			max_mem: None,
+			max_age: None,
 		}));
 		assert!(db.data_eq(&make_db(&[21, 3, 922, 94])));
 	}
@@ -670,6 +1191,7 @@ mod tests {
 		let (db, sdb) = make_test_db(PruningMode::Constrained(Constraints {
 			max_blocks: Some(1),
 			max_mem: None,
+			max_age: None,
 		}));
 		assert!(sdb.is_pruned(&H256::from_low_u64_be(0), 0));
 		assert!(sdb.is_pruned(&H256::from_low_u64_be(1), 1));
@@ -683,6 +1205,7 @@ mod tests {
 		let (db, sdb) = make_test_db(PruningMode::Constrained(Constraints {
 			max_blocks: Some(2),
 			max_mem: None,
+			max_age: None,
 		}));
 		assert!(sdb.is_pruned(&H256::from_low_u64_be(0), 0));
 		assert!(sdb.is_pruned(&H256::from_low_u64_be(1), 1));
@@ -694,7 +1217,7 @@ mod tests {
 	#[test]
 	fn detects_incompatible_mode() {
 		let mut db = make_db(&[]);
-		let state_db = StateDb::new(PruningMode::ArchiveAll, false, &db).unwrap();
+		let state_db = StateDb::new(PruningMode::ArchiveAll, false, "test", &db).unwrap();
 		db.commit(
 			&state_db
 				.insert_block::<io::Error>(
@@ -705,8 +1228,770 @@ mod tests {
 				)
 				.unwrap(),
 		);
-		let new_mode = PruningMode::Constrained(Constraints { max_blocks: Some(2), max_mem: None });
-		let state_db: Result<StateDb<H256, H256>, _> = StateDb::new(new_mode, false, &db);
+		let new_mode = PruningMode::Constrained(Constraints {
+			max_blocks: Some(2),
+			max_mem: None,
+			max_age: None,
+		});
+		let state_db: Result<StateDb<H256, H256>, _> = StateDb::new(new_mode, false, "test", &db);
 		assert!(state_db.is_err());
 	}
+
+	#[test]
+	fn reports_unknown_mode() {
+		let mut db = make_db(&[]);
+		db.meta.insert(to_meta_key(PRUNING_MODE, &()), b"from_the_future".to_vec());
+
+		let state_db: Result<StateDb<H256, H256>, _> =
+			StateDb::new(PruningMode::ArchiveAll, false, "test", &db);
+		match state_db {
+			Err(Error::UnknownPruningMode(mode)) => assert_eq!(mode, b"from_the_future".to_vec()),
+			_ => panic!("unknown pruning mode must be reported"),
+		}
+	}
+
+	fn make_genesis_db(mode: PruningMode) -> TestDb {
+		let mut db = make_db(&[]);
+		let state_db: StateDb<H256, H256> = StateDb::new(mode, false, "test", &db).unwrap();
+		db.commit(
+			&state_db
+				.insert_block::<io::Error>(
+					&H256::from_low_u64_be(0),
+					0,
+					&H256::from_low_u64_be(0),
+					make_changeset(&[], &[]),
+				)
+				.unwrap(),
+		);
+		db
+	}
+
+	#[test]
+	fn accepts_matching_constraints() {
+		let db = make_genesis_db(PruningMode::keep_blocks(2));
+		let state_db: Result<StateDb<H256, H256>, _> =
+			StateDb::new(PruningMode::keep_blocks(2), false, "test", &db);
+		assert!(state_db.is_ok());
+	}
+
+	#[test]
+	fn detects_incompatible_constraints() {
+		let db = make_genesis_db(PruningMode::keep_blocks(2));
+		let state_db: Result<StateDb<H256, H256>, _> =
+			StateDb::new(PruningMode::keep_blocks(3), false, "test", &db);
+		match state_db {
+			Err(Error::InvalidPruningConstraints(constraints)) => assert_eq!(
+				constraints,
+				Constraints { max_blocks: Some(2), max_mem: None, max_age: None }
+			),
+			_ => panic!("changed constraints must be reported"),
+		}
+	}
+
+	#[test]
+	fn detects_constrained_to_archive() {
+		let db = make_genesis_db(PruningMode::keep_blocks(2));
+		let state_db: Result<StateDb<H256, H256>, _> =
+			StateDb::new(PruningMode::ArchiveAll, false, "test", &db);
+		assert!(matches!(state_db, Err(Error::InvalidPruningMode(_))));
+	}
+
+	#[test]
+	fn accepts_constraints_missing_from_meta() {
+		let mut db = make_genesis_db(PruningMode::keep_blocks(2));
+		db.meta.remove(&to_meta_key(PRUNING_MODE_ARG, &()));
+		let state_db: Result<StateDb<H256, H256>, _> =
+			StateDb::new(PruningMode::keep_blocks(3), false, "test", &db);
+		assert!(state_db.is_ok());
+	}
+
+	#[test]
+	fn constraints_roundtrip_through_meta() {
+		let constraints =
+			Constraints { max_blocks: Some(256), max_mem: Some(64 * 1024 * 1024), max_age: None };
+		let db = make_genesis_db(PruningMode::Constrained(constraints.clone()));
+		let stored = db.meta.get(&to_meta_key(PRUNING_MODE_ARG, &())).unwrap();
+		assert_eq!(
+			decode_constraints(stored, Some(CURRENT_PRUNING_META_VERSION)).unwrap(),
+			constraints
+		);
+
+		let state_db: Result<StateDb<H256, H256>, _> =
+			StateDb::new(PruningMode::Constrained(constraints), false, "test", &db);
+		assert!(state_db.is_ok());
+	}
+
+	#[test]
+	fn accepts_changed_memory_budget_and_maximum_age() {
+		let constraints = Constraints { max_blocks: Some(256), max_mem: Some(1024), max_age: None };
+		let db = make_genesis_db(PruningMode::Constrained(constraints));
+		for constraints in [
+			Constraints { max_blocks: Some(256), max_mem: None, max_age: None },
+			Constraints { max_blocks: Some(256), max_mem: Some(2048), max_age: None },
+			Constraints {
+				max_blocks: Some(256),
+				max_mem: Some(1024),
+				max_age: Some(Duration::from_secs(3600)),
+			},
+		] {
+			let state_db: Result<StateDb<H256, H256>, _> =
+				StateDb::new(PruningMode::Constrained(constraints), false, "test", &db);
+			assert!(state_db.is_ok());
+		}
+	}
+
+	#[test]
+	fn decodes_constraints_without_memory_budget() {
+		let encoded = Some(256u32).encode();
+		assert_eq!(
+			decode_constraints(&encoded, None).unwrap(),
+			Constraints { max_blocks: Some(256), max_mem: None, max_age: None },
+		);
+	}
+
+	#[test]
+	fn max_age_roundtrips_through_meta() {
+		let constraints = Constraints {
+			max_blocks: Some(256),
+			max_mem: None,
+			max_age: Some(Duration::from_secs(72 * 3600)),
+		};
+		let db = make_genesis_db(PruningMode::Constrained(constraints.clone()));
+		let stored = db.meta.get(&to_meta_key(PRUNING_MODE_ARG, &())).unwrap();
+		assert_eq!(
+			decode_constraints(stored, Some(CURRENT_PRUNING_META_VERSION)).unwrap(),
+			constraints
+		);
+
+		let encoded = (Some(256u32), Some(1024u64)).encode();
+		assert_eq!(
+			decode_constraints(&encoded, None).unwrap(),
+			Constraints { max_blocks: Some(256), max_mem: Some(1024), max_age: None },
+		);
+	}
+
+	#[test]
+	fn stricter_bound_wins() {
+		let hours = |n: u64| Some(Duration::from_secs(n * 3600));
+		let constraints = Constraints { max_blocks: Some(256), max_mem: None, max_age: hours(72) };
+
+		// within both bounds
+		assert!(!constraints.exceeded_by(256, 0, hours(71)));
+		// too many blocks, all of them recent enough
+		assert!(constraints.exceeded_by(257, 0, hours(1)));
+		// few enough blocks, the oldest of them too old
+		assert!(constraints.exceeded_by(16, 0, hours(73)));
+		// the age is only bounded when set
+		let constraints = Constraints { max_age: None, ..constraints };
+		assert!(!constraints.exceeded_by(16, 0, hours(73)));
+	}
+
+	#[test]
+	fn prunes_past_max_blocks_over_memory_budget() {
+		let constrained = |max_mem| {
+			PruningMode::Constrained(Constraints { max_blocks: Some(16), max_mem, max_age: None })
+		};
+		let (unpruned, _) = make_test_db(PruningMode::keep_blocks(16));
+
+		let (db, sdb) = make_test_db(constrained(Some(1024 * 1024)));
+		assert!(!sdb.is_pruned(&H256::from_low_u64_be(21), 2));
+		assert!(db.data_eq(&unpruned));
+
+		let (db, sdb) = make_test_db(constrained(Some(0)));
+		assert!(sdb.is_pruned(&H256::from_low_u64_be(21), 2));
+		assert!(db.data_eq(&make_db(&[21, 3, 922, 94])));
+	}
+
+	#[test]
+	fn prunes_states_older_than_max_age() {
+		let (db, sdb) = make_test_db(PruningMode::Constrained(Constraints {
+			max_blocks: Some(16),
+			max_mem: None,
+			max_age: Some(Duration::from_secs(0)),
+		}));
+		assert!(sdb.is_pruned(&H256::from_low_u64_be(21), 2));
+		assert!(db.data_eq(&make_db(&[21, 3, 922, 94])));
+	}
+
+	#[test]
+	fn accepts_checksummed_constraints() {
+		let constraints = Constraints { max_blocks: Some(2), max_mem: None, max_age: None };
+		let encoded = encode_constraints(&constraints);
+		assert!(encoded.starts_with(PRUNING_MODE_ARG_MAGIC));
+		assert_eq!(
+			decode_constraints(&encoded, Some(CURRENT_PRUNING_META_VERSION)).unwrap(),
+			constraints
+		);
+
+		let db = make_genesis_db(PruningMode::keep_blocks(2));
+		assert_eq!(db.meta.get(&to_meta_key(PRUNING_MODE_ARG, &())), Some(&encoded));
+		assert!(
+			StateDb::<H256, H256>::new(PruningMode::keep_blocks(2), false, "test", &db).is_ok()
+		);
+	}
+
+	#[test]
+	fn rejects_corrupted_constraints() {
+		let mut encoded =
+			encode_constraints(&Constraints { max_blocks: Some(2), max_mem: None, max_age: None });
+		*encoded.last_mut().unwrap() ^= 1;
+		assert!(decode_constraints(&encoded, None).is_err());
+		assert!(decode_constraints(PRUNING_MODE_ARG_MAGIC, None).is_err());
+
+		let mut db = make_genesis_db(PruningMode::keep_blocks(2));
+		db.meta.insert(to_meta_key(PRUNING_MODE_ARG, &()), encoded);
+		let state_db: Result<StateDb<H256, H256>, _> =
+			StateDb::new(PruningMode::keep_blocks(2), false, "test", &db);
+		assert!(matches!(state_db, Err(Error::Decoding(_))));
+		assert!(StateDb::<H256, H256>::stored_pruning_mode(&db).is_err());
+	}
+
+	#[test]
+	fn accepts_unchecksummed_constraints() {
+		let mut db = make_genesis_db(PruningMode::keep_blocks(2));
+		db.meta.insert(to_meta_key(PRUNING_META_VERSION, &()), 1u32.encode());
+		db.meta
+			.insert(to_meta_key(PRUNING_MODE_ARG, &()), (Some(2u32), None::<u64>).encode());
+		assert!(
+			StateDb::<H256, H256>::new(PruningMode::keep_blocks(2), false, "test", &db).is_ok()
+		);
+		assert_eq!(
+			StateDb::<H256, H256>::stored_pruning_mode(&db).unwrap(),
+			Some(PruningMode::keep_blocks(2)),
+		);
+	}
+
+	#[test]
+	fn rejects_unchecksummed_constraints_of_checksummed_layout() {
+		let encoded = (Some(2u32), None::<u64>).encode();
+		assert!(decode_constraints(&encoded, Some(CHECKSUMMED_PRUNING_META_VERSION)).is_err());
+
+		let mut db = make_genesis_db(PruningMode::keep_blocks(2));
+		db.meta.insert(to_meta_key(PRUNING_MODE_ARG, &()), encoded);
+		let state_db: Result<StateDb<H256, H256>, _> =
+			StateDb::new(PruningMode::keep_blocks(2), false, "test", &db);
+		assert!(matches!(state_db, Err(Error::Decoding(_))));
+		assert!(StateDb::<H256, H256>::stored_pruning_mode(&db).is_err());
+	}
+
+	#[test]
+	fn checksums_constraints_with_crc32() {
+		assert_eq!(checksum(b"123456789"), 0xcbf43926u32.to_le_bytes());
+	}
+
+	fn migrate_meta(db: &mut TestDb) -> bool {
+		match StateDb::<H256, H256>::migrate_meta(db).unwrap() {
+			Some(commit) => {
+				db.commit(&commit);
+				true
+			},
+			None => false,
+		}
+	}
+
+	#[test]
+	fn meta_migration_is_idempotent() {
+		let mut db = make_genesis_db(PruningMode::keep_blocks(2));
+		db.meta.insert(to_meta_key(PRUNING_META_VERSION, &()), 1u32.encode());
+		db.meta
+			.insert(to_meta_key(PRUNING_MODE_ARG, &()), (Some(2u32), None::<u64>).encode());
+
+		assert!(migrate_meta(&mut db));
+		let migrated = db.meta.clone();
+		assert!(!migrate_meta(&mut db));
+		assert_eq!(db.meta, migrated);
+	}
+
+	#[test]
+	fn migrates_each_meta_layout() {
+		let constraints = Constraints { max_blocks: Some(2), max_mem: None, max_age: None };
+		let current = make_genesis_db(PruningMode::Constrained(constraints.clone())).meta;
+		let version_key = to_meta_key(PRUNING_META_VERSION, &());
+		let arg_key = to_meta_key(PRUNING_MODE_ARG, &());
+
+		// the current layout is left as is
+		let mut db = make_genesis_db(PruningMode::Constrained(constraints.clone()));
+		assert!(!migrate_meta(&mut db));
+		assert_eq!(db.meta, current);
+
+		// the first version, with unchecksummed constraints
+		let mut db = make_genesis_db(PruningMode::Constrained(constraints.clone()));
+		db.meta.insert(version_key.clone(), 1u32.encode());
+		db.meta.insert(arg_key.clone(), Some(2u32).encode());
+		assert!(migrate_meta(&mut db));
+		assert_eq!(db.meta, current);
+
+		// the unversioned layout, with or without the constraints
+		let mut db = make_genesis_db(PruningMode::Constrained(constraints.clone()));
+		db.meta.remove(&version_key);
+		db.meta.insert(arg_key.clone(), Some(2u32).encode());
+		assert!(migrate_meta(&mut db));
+		assert_eq!(db.meta, current);
+
+		let mut db = make_genesis_db(PruningMode::Constrained(constraints));
+		db.meta.remove(&version_key);
+		db.meta.remove(&arg_key);
+		assert!(migrate_meta(&mut db));
+		assert_eq!(db.meta.get(&version_key), current.get(&version_key));
+		assert_eq!(db.meta.get(&arg_key), None);
+		assert!(
+			StateDb::<H256, H256>::new(PruningMode::keep_blocks(16), false, "test", &db).is_ok()
+		);
+
+		let mut db = make_genesis_db(PruningMode::ArchiveAll);
+		db.meta.remove(&version_key);
+		assert!(migrate_meta(&mut db));
+		assert_eq!(db.meta, make_genesis_db(PruningMode::ArchiveAll).meta);
+
+		// nothing to migrate before the genesis block is written
+		let mut db = make_db(&[]);
+		assert!(!migrate_meta(&mut db));
+	}
+
+	#[test]
+	fn meta_migration_rejects_newer_version() {
+		let mut db = make_genesis_db(PruningMode::keep_blocks(2));
+		let version = CURRENT_PRUNING_META_VERSION + 1;
+		db.meta.insert(to_meta_key(PRUNING_META_VERSION, &()), version.encode());
+		let migrated = StateDb::<H256, H256>::migrate_meta(&db);
+		assert!(matches!(migrated, Err(Error::UnsupportedPruningMetaVersion(v)) if v == version));
+	}
+
+	#[test]
+	fn refuses_migrating_to_archive_all() {
+		let genesis = (H256::from_low_u64_be(0), 0);
+		for from in [PruningMode::ArchiveCanonical, PruningMode::keep_blocks(16)] {
+			let db = make_genesis_db(from.clone());
+			let to = PruningMode::ArchiveAll;
+			let migration =
+				StateDb::<H256, H256>::migrate_pruning_mode(&db, &from, &to, genesis, "test");
+			assert!(matches!(migration, Err(Error::UnsupportedPruningMigration(_, _))));
+		}
+		let db = make_genesis_db(PruningMode::ArchiveAll);
+		assert!(StateDb::<H256, H256>::migrate_pruning_mode(
+			&db,
+			&PruningMode::ArchiveAll,
+			&PruningMode::ArchiveAll,
+			genesis,
+			"test",
+		)
+		.is_ok());
+	}
+
+	#[test]
+	fn refuses_migrating_constrained_to_archive() {
+		let (db, _) = make_test_db(PruningMode::keep_blocks(0));
+		let from = PruningMode::keep_blocks(0);
+		let last_finalized = (H256::from_low_u64_be(3), 3);
+		for to in [PruningMode::ArchiveCanonical, PruningMode::ArchiveAll] {
+			let migration = StateDb::<H256, H256>::migrate_pruning_mode(
+				&db,
+				&from,
+				&to,
+				last_finalized,
+				"test",
+			);
+			assert!(matches!(migration, Err(Error::UnsupportedPruningMigration(_, _))));
+		}
+
+		// the pruned states are never reported as readable again
+		let state_db: StateDb<H256, H256> = StateDb::new(from, false, "test", &db).unwrap();
+		assert!(state_db.is_pruned(&H256::from_low_u64_be(1), 1));
+		assert!(state_db.pin(&H256::from_low_u64_be(1)).is_err());
+		assert!(StateDb::<H256, H256>::migrate_pruning_mode(
+			&db,
+			&PruningMode::keep_blocks(0),
+			&PruningMode::keep_blocks(16),
+			last_finalized,
+			"test",
+		)
+		.is_ok());
+	}
+
+	#[test]
+	fn reports_state_canonicalized_before_migration_as_pruned() {
+		let (mut db, _) = make_test_db(PruningMode::ArchiveCanonical);
+		let to = PruningMode::keep_blocks(1);
+		db.commit(
+			&StateDb::<H256, H256>::migrate_pruning_mode(
+				&db,
+				&PruningMode::ArchiveCanonical,
+				&to,
+				(H256::from_low_u64_be(3), 3),
+				"test",
+			)
+			.unwrap(),
+		);
+
+		// only the state of the last canonicalized block is kept
+		let state_db: StateDb<H256, H256> = StateDb::new(to, false, "test", &db).unwrap();
+		for (hash, number) in [(1, 1), (21, 2)] {
+			assert!(state_db.is_pruned(&H256::from_low_u64_be(hash), number));
+			assert!(state_db.pin(&H256::from_low_u64_be(hash)).is_err());
+		}
+		assert!(!state_db.is_pruned(&H256::from_low_u64_be(3), 3));
+
+		// the state of block 3 holds node 94, which block 4 deletes
+		assert!(state_db.pin(&H256::from_low_u64_be(3)).is_ok());
+		db.commit(&state_db.canonicalize_block::<io::Error>(&H256::from_low_u64_be(4)).unwrap());
+		state_db.apply_pending();
+		assert!(!state_db.is_pruned(&H256::from_low_u64_be(3), 3));
+		assert!(db.data_eq(&make_db(&[1, 21, 3, 4, 91, 921, 922, 93, 94])));
+
+		// the state of block 3 is reported as pruned once it is unreadable
+		state_db.unpin(&H256::from_low_u64_be(3));
+		db.commit(
+			&state_db
+				.insert_block::<io::Error>(
+					&H256::from_low_u64_be(5),
+					5,
+					&H256::from_low_u64_be(4),
+					make_changeset(&[5], &[]),
+				)
+				.unwrap(),
+		);
+		db.commit(&state_db.canonicalize_block::<io::Error>(&H256::from_low_u64_be(5)).unwrap());
+		state_db.apply_pending();
+		assert!(state_db.is_pruned(&H256::from_low_u64_be(3), 3));
+		assert!(!state_db.is_pruned(&H256::from_low_u64_be(5), 5));
+		assert!(db.data_eq(&make_db(&[1, 21, 3, 4, 5, 91, 921, 922, 93])));
+
+		let state_db: StateDb<H256, H256> =
+			StateDb::new(PruningMode::keep_blocks(1), false, "test", &db).unwrap();
+		assert!(state_db.is_pruned(&H256::from_low_u64_be(3), 3));
+		assert!(!state_db.is_pruned(&H256::from_low_u64_be(5), 5));
+	}
+
+	#[test]
+	fn migrates_archive_canonical_to_constrained() {
+		let mut db = make_db(&[]);
+		let state_db: StateDb<H256, H256> =
+			StateDb::new(PruningMode::ArchiveCanonical, false, "test", &db).unwrap();
+		db.commit(
+			&state_db
+				.insert_block::<io::Error>(
+					&H256::from_low_u64_be(0),
+					0,
+					&H256::from_low_u64_be(0),
+					make_changeset(&[], &[]),
+				)
+				.unwrap(),
+		);
+		db.commit(&state_db.canonicalize_block::<io::Error>(&H256::from_low_u64_be(0)).unwrap());
+
+		let to = PruningMode::keep_blocks(16);
+		db.commit(
+			&StateDb::<H256, H256>::migrate_pruning_mode(
+				&db,
+				&PruningMode::ArchiveCanonical,
+				&to,
+				(H256::from_low_u64_be(0), 0),
+				"test",
+			)
+			.unwrap(),
+		);
+
+		assert_eq!(db.meta.get(&to_meta_key(PRUNING_MODE, &())).unwrap(), b"constrained");
+		assert_eq!(
+			decode_constraints(
+				db.meta.get(&to_meta_key(PRUNING_MODE_ARG, &())).unwrap(),
+				Some(CURRENT_PRUNING_META_VERSION)
+			)
+			.unwrap(),
+			Constraints { max_blocks: Some(16), max_mem: None, max_age: None },
+		);
+		// the pruning window starts at the canonicalized genesis
+		let state_db: StateDb<H256, H256> = StateDb::new(to, false, "test", &db).unwrap();
+		assert!(!state_db.is_pruned(&H256::from_low_u64_be(0), 0));
+	}
+
+	#[test]
+	fn migrates_archive_all_to_constrained() {
+		let (mut db, _) = make_test_db(PruningMode::ArchiveAll);
+		let to = PruningMode::keep_blocks(1);
+		db.commit(
+			&StateDb::<H256, H256>::migrate_pruning_mode(
+				&db,
+				&PruningMode::ArchiveAll,
+				&to,
+				(H256::from_low_u64_be(4), 4),
+				"test",
+			)
+			.unwrap(),
+		);
+		assert_eq!(
+			StateDb::<H256, H256>::stored_pruning_mode(&db).unwrap(),
+			Some(PruningMode::keep_blocks(1)),
+		);
+
+		// the canonical chain is tracked from the last finalized block on
+		let state_db: StateDb<H256, H256> = StateDb::new(to, false, "test", &db).unwrap();
+		assert_eq!(state_db.best_canonical(), Some(4));
+		assert!(state_db.is_pruned(&H256::from_low_u64_be(3), 3));
+		assert!(!state_db.is_pruned(&H256::from_low_u64_be(4), 4));
+		db.commit(
+			&state_db
+				.insert_block::<io::Error>(
+					&H256::from_low_u64_be(5),
+					5,
+					&H256::from_low_u64_be(4),
+					make_changeset(&[5], &[4]),
+				)
+				.unwrap(),
+		);
+		db.commit(&state_db.canonicalize_block::<io::Error>(&H256::from_low_u64_be(5)).unwrap());
+		state_db.apply_pending();
+		assert!(state_db.is_pruned(&H256::from_low_u64_be(4), 4));
+		assert!(!state_db.is_pruned(&H256::from_low_u64_be(5), 5));
+		assert!(db.data_eq(&make_db(&[1, 21, 22, 3, 4, 5, 91, 921, 922, 93, 94])));
+	}
+
+	#[test]
+	fn migration_checks_the_current_mode() {
+		let db = make_genesis_db(PruningMode::keep_blocks(2));
+		let migration = StateDb::<H256, H256>::migrate_pruning_mode(
+			&db,
+			&PruningMode::ArchiveAll,
+			&PruningMode::keep_blocks(16),
+			(H256::from_low_u64_be(0), 0),
+			"test",
+		);
+		assert!(matches!(migration, Err(Error::InvalidPruningMode(_))));
+	}
+
+	#[test]
+	fn loads_current_meta_version() {
+		let db = make_genesis_db(PruningMode::keep_blocks(2));
+		assert_eq!(
+			db.meta.get(&to_meta_key(PRUNING_META_VERSION, &())).unwrap(),
+			&CURRENT_PRUNING_META_VERSION.encode(),
+		);
+		assert!(
+			StateDb::<H256, H256>::new(PruningMode::keep_blocks(2), false, "test", &db).is_ok()
+		);
+	}
+
+	#[test]
+	fn loads_legacy_unversioned_meta() {
+		let mut db = make_genesis_db(PruningMode::keep_blocks(2));
+		db.meta.remove(&to_meta_key(PRUNING_META_VERSION, &()));
+		db.meta.insert(to_meta_key(PRUNING_MODE_ARG, &()), Some(2u32).encode());
+		assert!(
+			StateDb::<H256, H256>::new(PruningMode::keep_blocks(2), false, "test", &db).is_ok()
+		);
+		assert!(
+			StateDb::<H256, H256>::new(PruningMode::keep_blocks(3), false, "test", &db).is_err()
+		);
+	}
+
+	#[test]
+	fn rejects_newer_meta_version() {
+		let mut db = make_genesis_db(PruningMode::keep_blocks(2));
+		let version = CURRENT_PRUNING_META_VERSION + 1;
+		db.meta.insert(to_meta_key(PRUNING_META_VERSION, &()), version.encode());
+		let state_db: Result<StateDb<H256, H256>, _> =
+			StateDb::new(PruningMode::keep_blocks(2), false, "test", &db);
+		assert!(matches!(state_db, Err(Error::UnsupportedPruningMetaVersion(v)) if v == version));
+	}
+
+	#[test]
+	fn archive_plan_discards_nothing() {
+		assert_eq!(
+			PruningMode::ArchiveAll.plan(1000, 990),
+			PruningPlan { discarded: 0..0, kept: 0..1001, discards_non_canonical: false },
+		);
+	}
+
+	#[test]
+	fn archive_canonical_plan_only_discards_forks() {
+		assert_eq!(
+			PruningMode::ArchiveCanonical.plan(1000, 990),
+			PruningPlan { discarded: 0..0, kept: 0..1001, discards_non_canonical: true },
+		);
+	}
+
+	#[test]
+	fn constrained_plan_discards_blocks_past_the_window() {
+		assert_eq!(
+			PruningMode::keep_blocks(256).plan(1000, 990),
+			PruningPlan { discarded: 0..735, kept: 735..1001, discards_non_canonical: true },
+		);
+		// the window is not full yet
+		assert_eq!(
+			PruningMode::keep_blocks(256).plan(100, 90),
+			PruningPlan { discarded: 0..0, kept: 0..101, discards_non_canonical: true },
+		);
+		// the highest block number does not overflow
+		assert_eq!(
+			PruningMode::keep_blocks(256).plan(u64::MAX, u64::MAX),
+			PruningPlan {
+				discarded: 0..u64::MAX - 256,
+				kept: u64::MAX - 256..u64::MAX,
+				discards_non_canonical: true,
+			},
+		);
+	}
+
+	#[test]
+	fn fetches_stored_mode() {
+		for mode in [
+			PruningMode::ArchiveAll,
+			PruningMode::ArchiveCanonical,
+			PruningMode::Constrained(Constraints {
+				max_blocks: Some(2),
+				max_mem: Some(1024),
+				max_age: None,
+			}),
+		] {
+			let db = make_genesis_db(mode.clone());
+			assert_eq!(StateDb::<H256, H256>::stored_pruning_mode(&db).unwrap(), Some(mode));
+		}
+		assert_eq!(StateDb::<H256, H256>::stored_pruning_mode(&make_db(&[])).unwrap(), None);
+	}
+
+	#[test]
+	fn clears_stored_mode() {
+		let mut db = make_genesis_db(PruningMode::keep_blocks(2));
+		db.commit(&StateDb::<H256, H256>::clear_pruning_mode());
+
+		assert_eq!(StateDb::<H256, H256>::stored_pruning_mode(&db).unwrap(), None);
+		assert!(db.meta.get(&to_meta_key(PRUNING_MODE_ARG, &())).is_none());
+		assert!(StateDb::<H256, H256>::new(PruningMode::ArchiveAll, false, "test", &db).is_ok());
+	}
+
+	#[test]
+	fn records_the_mode_opened_after_clearing() {
+		let mut db = make_genesis_db(PruningMode::keep_blocks(2));
+		db.commit(&StateDb::<H256, H256>::clear_pruning_mode());
+
+		let state_db: StateDb<H256, H256> =
+			StateDb::new(PruningMode::keep_blocks(4), false, "test", &db).unwrap();
+		db.commit(
+			&state_db
+				.insert_block::<io::Error>(
+					&H256::from_low_u64_be(1),
+					1,
+					&H256::from_low_u64_be(0),
+					make_changeset(&[], &[]),
+				)
+				.unwrap(),
+		);
+		state_db.apply_pending();
+		assert_eq!(
+			StateDb::<H256, H256>::stored_pruning_mode(&db).unwrap(),
+			Some(PruningMode::keep_blocks(4)),
+		);
+
+		// recorded once
+		let commit = state_db
+			.insert_block::<io::Error>(
+				&H256::from_low_u64_be(2),
+				2,
+				&H256::from_low_u64_be(1),
+				make_changeset(&[], &[]),
+			)
+			.unwrap();
+		assert!(!commit.meta.inserted.iter().any(|(k, _)| *k == to_meta_key(PRUNING_MODE, &())));
+		assert!(matches!(
+			StateDb::<H256, H256>::new(PruningMode::keep_blocks(2), false, "test", &db),
+			Err(Error::InvalidPruningConstraints(_)),
+		));
+	}
+
+	#[test]
+	fn records_the_writer_version() {
+		let mut db = make_genesis_db(PruningMode::keep_blocks(2));
+		assert_eq!(
+			StateDb::<H256, H256>::stored_pruning_mode_writer(&db).unwrap().as_deref(),
+			Some("test"),
+		);
+
+		db.meta.remove(&to_meta_key(PRUNING_MODE_WRITER_VERSION, &()));
+		assert_eq!(StateDb::<H256, H256>::stored_pruning_mode_writer(&db).unwrap(), None);
+		assert!(
+			StateDb::<H256, H256>::new(PruningMode::keep_blocks(2), false, "test", &db).is_ok()
+		);
+	}
+
+	#[test]
+	fn pruning_meta_keys_are_the_raw_suffixes() {
+		// `()` encodes to nothing, so the keys never depended on the encoding of the prefix.
+		for suffix in
+			[PRUNING_META_VERSION, PRUNING_MODE, PRUNING_MODE_ARG, PRUNING_MODE_WRITER_VERSION]
+		{
+			assert_eq!(to_meta_key(suffix, &()), suffix);
+		}
+	}
+
+	#[test]
+	fn pruning_modes_roundtrip_through_strings() {
+		for mode in [
+			PruningMode::ArchiveAll,
+			PruningMode::ArchiveCanonical,
+			PruningMode::keep_blocks(256),
+			PruningMode::Constrained(Constraints {
+				max_blocks: None,
+				max_mem: None,
+				max_age: None,
+			}),
+		] {
+			assert_eq!(mode.to_string().parse::<PruningMode>(), Ok(mode));
+		}
+
+		assert_eq!(PruningMode::keep_blocks(256).to_string(), "constrained:256");
+		assert_eq!(PruningMode::keep_blocks(256).as_str(), "constrained");
+		assert_eq!("256".parse::<PruningMode>(), Ok(PruningMode::keep_blocks(256)));
+		assert_eq!("archive_canonical".parse::<PruningMode>(), Ok(PruningMode::ArchiveCanonical));
+		assert!("constrained:many".parse::<PruningMode>().is_err());
+		assert!("everything".parse::<PruningMode>().is_err());
+	}
+
+	#[test]
+	fn parses_blocks_and_hours_constraints() {
+		let mode = PruningMode::Constrained(Constraints {
+			max_blocks: Some(256),
+			max_mem: None,
+			max_age: Some(Duration::from_secs(72 * 3600)),
+		});
+		assert_eq!("256:72h".parse::<PruningMode>(), Ok(mode.clone()));
+		assert_eq!("constrained:256:72h".parse::<PruningMode>(), Ok(mode.clone()));
+		assert_eq!(mode.to_string(), "constrained:256:72h");
+
+		assert!("256:72".parse::<PruningMode>().is_err());
+		assert!("256:0h".parse::<PruningMode>().is_err());
+		assert!("256:h".parse::<PruningMode>().is_err());
+		assert!(":72h".parse::<PruningMode>().is_err());
+	}
+
+	#[test]
+	fn stored_mode_ids_parse_as_pruning_modes() {
+		for mode in [PruningMode::ArchiveAll, PruningMode::ArchiveCanonical, PruningMode::default()]
+		{
+			let id = std::str::from_utf8(mode.id()).unwrap();
+			assert_eq!(id.parse::<PruningMode>().unwrap().as_str(), mode.as_str());
+		}
+	}
+
+	#[test]
+	fn shrinking_changes_are_reported() {
+		use PruningMode::{ArchiveAll, ArchiveCanonical};
+		let keep = PruningMode::keep_blocks;
+
+		assert_eq!(ArchiveAll.shrinks_to(&ArchiveCanonical), Some(PruningShrink::NonCanonical));
+		assert_eq!(ArchiveAll.shrinks_to(&keep(16)), Some(PruningShrink::Canonical(16)));
+		assert_eq!(ArchiveCanonical.shrinks_to(&keep(16)), Some(PruningShrink::Canonical(16)));
+		assert_eq!(keep(256).shrinks_to(&keep(16)), Some(PruningShrink::Window(240)));
+	}
+
+	#[test]
+	fn widening_changes_are_not_reported() {
+		use PruningMode::{ArchiveAll, ArchiveCanonical};
+		let keep = PruningMode::keep_blocks;
+
+		assert_eq!(ArchiveAll.shrinks_to(&ArchiveAll), None);
+		assert_eq!(ArchiveCanonical.shrinks_to(&ArchiveAll), None);
+		assert_eq!(keep(16).shrinks_to(&ArchiveCanonical), None);
+		assert_eq!(keep(16).shrinks_to(&keep(16)), None);
+		assert_eq!(keep(16).shrinks_to(&keep(256)), None);
+	}
 }
@@ -241,6 +241,16 @@ impl<BlockHash: Hash, Key: Hash> NonCanonicalOverlay<BlockHash, Key> {
 		})
 	}
 
+	/// Start tracking the blocks of a database that did not, right after the canonical block
+	/// `hash` numbered `number`. Adds changes to `commit`.
+	pub fn start_after(hash: &BlockHash, number: u64, commit: &mut CommitSet<Key>) {
+		let last_canonicalized = (hash.clone(), number);
+		commit
+			.meta
+			.inserted
+			.push((to_meta_key(LAST_CANONICAL, &()), last_canonicalized.encode()));
+	}
+
 	/// Insert a new block into the overlay. If inserted on the second level or lover expects parent
 	/// to be present in the window.
 	pub fn insert<E: fmt::Debug>(
@@ -22,12 +22,16 @@
 //! There is also a global index of node key to block number.
 //! If a node is re-inserted into the window it gets removed from
 //! the death list.
-//! The changes are journaled in the DB.
+//! The changes are journaled in the DB, along with the time each block was canonicalized at.
 
 use crate::{to_meta_key, CommitSet, Error, Hash, MetaDb};
 use codec::{Decode, Encode};
 use log::{trace, warn};
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::{
+	collections::{HashMap, HashSet, VecDeque},
+	mem,
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 const LAST_PRUNED: &[u8] = b"last_pruned";
 const PRUNING_JOURNAL: &[u8] = b"pruning_journal";
@@ -37,10 +41,15 @@ const PRUNING_JOURNAL: &[u8] = b"pruning_journal";
 pub struct RefWindow<BlockHash: Hash, Key: Hash> {
 	/// A queue of keys that should be deleted for each block in the pruning window.
 	death_rows: VecDeque<DeathRow<BlockHash, Key>>,
+	/// When each of `death_rows` was added to the window, in seconds since the Unix epoch. The
+	/// rows journaled before it was recorded count as added when the window was loaded.
+	canonicalized_at: VecDeque<u64>,
 	/// An index that maps each key from `death_rows` to block number.
 	death_index: HashMap<Key, u64>,
 	/// Block number that corresponds to the front of `death_rows`.
 	pending_number: u64,
+	/// Estimated memory used by `death_rows`, including the ones pending pruning.
+	mem_used: usize,
 	/// Number of call of `note_canonical` after
 	/// last call `apply_pending` or `revert_pending`
 	pending_canonicalizations: usize,
@@ -60,6 +69,13 @@ struct DeathRow<BlockHash: Hash, Key: Hash> {
 	deleted: HashSet<Key>,
 }
 
+impl<BlockHash: Hash, Key: Hash> DeathRow<BlockHash, Key> {
+	/// Estimated memory used by the row and the keys it deletes.
+	fn mem_used(&self) -> usize {
+		mem::size_of::<Self>() + self.journal_key.len() + self.deleted.len() * mem::size_of::<Key>()
+	}
+}
+
 #[derive(Encode, Decode)]
 struct JournalRecord<BlockHash: Hash, Key: Hash> {
 	hash: BlockHash,
@@ -71,6 +87,30 @@ fn to_journal_key(block: u64) -> Vec<u8> {
 	to_meta_key(PRUNING_JOURNAL, &block)
 }
 
+/// The current time, in seconds since the Unix epoch.
+fn now() -> u64 {
+	SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs())
+}
+
+/// Encode a journal record, followed by the time its block was canonicalized at.
+fn encode_journal_record<BlockHash: Hash, Key: Hash>(
+	record: &JournalRecord<BlockHash, Key>,
+	canonicalized_at: u64,
+) -> Vec<u8> {
+	let mut encoded = record.encode();
+	canonicalized_at.encode_to(&mut encoded);
+	encoded
+}
+
+/// Decode a journal record and the time its block was canonicalized at, if recorded.
+fn decode_journal_record<BlockHash: Hash, Key: Hash>(
+	mut encoded: &[u8],
+) -> Result<(JournalRecord<BlockHash, Key>, Option<u64>), codec::Error> {
+	let record = Decode::decode(&mut encoded)?;
+	let canonicalized_at = if encoded.is_empty() { None } else { Some(u64::decode(&mut encoded)?) };
+	Ok((record, canonicalized_at))
+}
+
 impl<BlockHash: Hash, Key: Hash> RefWindow<BlockHash, Key> {
 	pub fn new<D: MetaDb>(
 		db: &D,
@@ -84,26 +124,30 @@ impl<BlockHash: Hash, Key: Hash> RefWindow<BlockHash, Key> {
 		let mut block = pending_number;
 		let mut pruning = RefWindow {
 			death_rows: Default::default(),
+			canonicalized_at: Default::default(),
 			death_index: Default::default(),
 			pending_number,
+			mem_used: 0,
 			pending_canonicalizations: 0,
 			pending_prunings: 0,
 			count_insertions,
 		};
 		// read the journal
 		trace!(target: "state-db", "Reading pruning journal. Pending #{}", pending_number);
+		let loaded_at = now();
 		loop {
 			let journal_key = to_journal_key(block);
 			match db.get_meta(&journal_key).map_err(|e| Error::Db(e))? {
 				Some(record) => {
-					let record: JournalRecord<BlockHash, Key> =
-						Decode::decode(&mut record.as_slice())?;
+					let (record, canonicalized_at): (JournalRecord<BlockHash, Key>, _) =
+						decode_journal_record(&record)?;
 					trace!(target: "state-db", "Pruning journal entry {} ({} inserted, {} deleted)", block, record.inserted.len(), record.deleted.len());
 					pruning.import(
 						&record.hash,
 						journal_key,
 						record.inserted.into_iter(),
 						record.deleted,
+						canonicalized_at.unwrap_or(loaded_at),
 					);
 				},
 				None => break,
@@ -113,18 +157,42 @@ impl<BlockHash: Hash, Key: Hash> RefWindow<BlockHash, Key> {
 		Ok(pruning)
 	}
 
+	/// Start the window of a database that did not maintain one at the canonical block `hash`,
+	/// numbered `number`, which deletes nothing once pruned. The older blocks are left out of the
+	/// window: pruning the blocks canonicalized afterwards deletes the nodes their state shares
+	/// with the later ones. Adds changes to `commit`.
+	pub fn start_at(hash: &BlockHash, number: u64, commit: &mut CommitSet<Key>) {
+		let journal_record = JournalRecord::<BlockHash, Key> {
+			hash: hash.clone(),
+			inserted: vec![],
+			deleted: vec![],
+		};
+		commit
+			.meta
+			.inserted
+			.push((to_journal_key(number), encode_journal_record(&journal_record, now())));
+		match number.checked_sub(1) {
+			Some(last_pruned) =>
+				commit.meta.inserted.push((to_meta_key(LAST_PRUNED, &()), last_pruned.encode())),
+			None => commit.meta.deleted.push(to_meta_key(LAST_PRUNED, &())),
+		}
+	}
+
 	fn import<I: IntoIterator<Item = Key>>(
 		&mut self,
 		hash: &BlockHash,
 		journal_key: Vec<u8>,
 		inserted: I,
 		deleted: Vec<Key>,
+		canonicalized_at: u64,
 	) {
 		if self.count_insertions {
 			// remove all re-inserted keys from death rows
 			for k in inserted {
 				if let Some(block) = self.death_index.remove(&k) {
-					self.death_rows[(block - self.pending_number) as usize].deleted.remove(&k);
+					if self.death_rows[(block - self.pending_number) as usize].deleted.remove(&k) {
+						self.mem_used -= mem::size_of::<Key>();
+					}
 				}
 			}
 
@@ -134,11 +202,11 @@ impl<BlockHash: Hash, Key: Hash> RefWindow<BlockHash, Key> {
 				self.death_index.insert(k.clone(), imported_block);
 			}
 		}
-		self.death_rows.push_back(DeathRow {
-			hash: hash.clone(),
-			deleted: deleted.into_iter().collect(),
-			journal_key,
-		});
+		let row =
+			DeathRow { hash: hash.clone(), deleted: deleted.into_iter().collect(), journal_key };
+		self.mem_used += row.mem_used();
+		self.death_rows.push_back(row);
+		self.canonicalized_at.push_back(canonicalized_at);
 	}
 
 	pub fn window_size(&self) -> u64 {
@@ -149,8 +217,19 @@ impl<BlockHash: Hash, Key: Hash> RefWindow<BlockHash, Key> {
 		self.death_rows.get(self.pending_prunings).map(|r| r.hash.clone())
 	}
 
+	/// How long ago the next block to prune was added to the window.
+	pub fn next_age(&self) -> Option<Duration> {
+		let now = now();
+		self.canonicalized_at
+			.get(self.pending_prunings)
+			.map(|at| Duration::from_secs(now.saturating_sub(*at)))
+	}
+
+	/// Estimated memory used by the window, leaving out the blocks pending pruning.
 	pub fn mem_used(&self) -> usize {
-		0
+		let pruned: usize =
+			self.death_rows.iter().take(self.pending_prunings).map(DeathRow::mem_used).sum();
+		self.mem_used - pruned
 	}
 
 	pub fn pending(&self) -> u64 {
@@ -187,12 +266,17 @@ impl<BlockHash: Hash, Key: Hash> RefWindow<BlockHash, Key> {
 		let journal_record = JournalRecord { hash: hash.clone(), inserted, deleted };
 		let block = self.pending_number + self.death_rows.len() as u64;
 		let journal_key = to_journal_key(block);
-		commit.meta.inserted.push((journal_key.clone(), journal_record.encode()));
+		let canonicalized_at = now();
+		commit
+			.meta
+			.inserted
+			.push((journal_key.clone(), encode_journal_record(&journal_record, canonicalized_at)));
 		self.import(
 			&journal_record.hash,
 			journal_key,
 			journal_record.inserted.into_iter(),
 			journal_record.deleted,
+			canonicalized_at,
 		);
 		self.pending_canonicalizations += 1;
 	}
@@ -205,6 +289,8 @@ impl<BlockHash: Hash, Key: Hash> RefWindow<BlockHash, Key> {
 				.death_rows
 				.pop_front()
 				.expect("pending_prunings is always < death_rows.len()");
+			self.mem_used -= pruned.mem_used();
+			self.canonicalized_at.pop_front();
 			trace!(target: "state-db", "Applying pruning {:?} ({} deleted)", pruned.hash, pruned.deleted.len());
 			if self.count_insertions {
 				for k in pruned.deleted.iter() {
@@ -223,7 +309,11 @@ impl<BlockHash: Hash, Key: Hash> RefWindow<BlockHash, Key> {
 		// `death_index` We don't bother to track and revert that for now. This means that a few
 		// nodes might end up no being deleted in case transaction fails and `revert_pending` is
 		// called.
-		self.death_rows.truncate(self.death_rows.len() - self.pending_canonicalizations);
+		let kept = self.death_rows.len() - self.pending_canonicalizations;
+		let reverted: usize = self.death_rows.iter().skip(kept).map(DeathRow::mem_used).sum();
+		self.mem_used -= reverted;
+		self.death_rows.truncate(kept);
+		self.canonicalized_at.truncate(self.death_rows.len());
 		if self.count_insertions {
 			let new_max_block = self.death_rows.len() as u64 + self.pending_number;
 			self.death_index.retain(|_, block| *block < new_max_block);
@@ -235,18 +325,79 @@ impl<BlockHash: Hash, Key: Hash> RefWindow<BlockHash, Key> {
 
 #[cfg(test)]
 mod tests {
-	use super::RefWindow;
+	use super::{
+		decode_journal_record, encode_journal_record, now, to_journal_key, JournalRecord, RefWindow,
+	};
 	use crate::{
 		test::{make_commit, make_db, TestDb},
 		CommitSet,
 	};
+	use codec::Encode;
 	use sp_core::H256;
+	use std::time::Duration;
 
 	fn check_journal(pruning: &RefWindow<H256, H256>, db: &TestDb) {
 		let restored: RefWindow<H256, H256> = RefWindow::new(db, pruning.count_insertions).unwrap();
 		assert_eq!(pruning.pending_number, restored.pending_number);
 		assert_eq!(pruning.death_rows, restored.death_rows);
 		assert_eq!(pruning.death_index, restored.death_index);
+		assert_eq!(pruning.mem_used, restored.mem_used);
+		assert_eq!(pruning.canonicalized_at, restored.canonicalized_at);
+	}
+
+	#[test]
+	fn restores_canonicalization_times() {
+		let mut db = make_db(&[1, 2]);
+		let mut pruning: RefWindow<H256, H256> = RefWindow::new(&db, true).unwrap();
+		let mut commit = make_commit(&[3], &[1]);
+		pruning.note_canonical(&H256::random(), &mut commit);
+		db.commit(&commit);
+		pruning.apply_pending();
+		check_journal(&pruning, &db);
+
+		// a block canonicalized a day ago is as old once the window is reloaded
+		let journal_key = to_journal_key(0);
+		let (record, _): (JournalRecord<H256, H256>, _) =
+			decode_journal_record(&db.meta[&journal_key]).unwrap();
+		let day = 24 * 3600;
+		db.meta.insert(journal_key.clone(), encode_journal_record(&record, now() - day));
+		let restored: RefWindow<H256, H256> = RefWindow::new(&db, true).unwrap();
+		assert!(restored.next_age().unwrap() >= Duration::from_secs(day));
+
+		// the blocks journaled before the time was recorded count as just canonicalized
+		db.meta.insert(journal_key, record.encode());
+		let restored: RefWindow<H256, H256> = RefWindow::new(&db, true).unwrap();
+		assert!(restored.next_age().unwrap() < Duration::from_secs(day));
+		assert_eq!(restored.death_rows, pruning.death_rows);
+	}
+
+	#[test]
+	fn tracks_memory_used() {
+		let mut db = make_db(&[1, 2, 3]);
+		let mut pruning: RefWindow<H256, H256> = RefWindow::new(&db, true).unwrap();
+		assert_eq!(pruning.mem_used(), 0);
+
+		let mut commit = make_commit(&[4], &[1, 2]);
+		pruning.note_canonical(&H256::random(), &mut commit);
+		db.commit(&commit);
+		let one_block = pruning.mem_used();
+		assert!(one_block > 2 * std::mem::size_of::<H256>());
+
+		let mut commit = make_commit(&[5], &[3]);
+		pruning.note_canonical(&H256::random(), &mut commit);
+		db.commit(&commit);
+		pruning.apply_pending();
+		let two_blocks = pruning.mem_used();
+		assert_eq!(two_blocks - one_block, one_block - std::mem::size_of::<H256>());
+		check_journal(&pruning, &db);
+
+		let mut commit = CommitSet::default();
+		pruning.prune_one(&mut commit);
+		assert_eq!(pruning.mem_used(), two_blocks - one_block);
+		db.commit(&commit);
+		pruning.apply_pending();
+		assert_eq!(pruning.mem_used(), two_blocks - one_block);
+		check_journal(&pruning, &db);
 	}
 
 	#[test]
@@ -26,15 +26,38 @@
 //!
 //! See [`sc-service::builder::RpcExtensionBuilder`] for more details.
 
-use std::{marker::PhantomData, sync::Arc};
-
-use crate::mpsc::{tracing_unbounded, TracingUnboundedReceiver, TracingUnboundedSender};
+use std::{
+	fmt,
+	future::Future,
+	marker::PhantomData,
+	pin::Pin,
+	sync::Arc,
+	task::{Context, Poll},
+	time::{Duration, Instant},
+};
 
+use futures::{
+	channel::oneshot,
+	future::{AbortHandle, Abortable, Either},
+	stream::{FusedStream, FuturesUnordered, Stream, StreamExt},
+	task::{Spawn, SpawnError, SpawnExt},
+	FutureExt,
+};
 use parking_lot::Mutex;
 
-/// Collection of channel sending endpoints shared with the receiver side
-/// so they can register themselves.
-type SharedSenders<Payload> = Arc<Mutex<Vec<TracingUnboundedSender<Payload>>>>;
+use crate::mpsc::TracingUnboundedReceiver;
+
+mod batching;
+mod multiplexed;
+mod registry;
+#[cfg(test)]
+mod tests;
+
+use registry::{Envelope, Filter, Metrics, Queue, Registry, SharedRegistry, SubscriptionGuard};
+
+pub use batching::BatchingSender;
+pub use multiplexed::{MultiplexedPayload, MultiplexedStream};
+pub use registry::{Backpressure, CloseReason, FilterHandle};
 
 /// Trait used to define the "tracing key" string used to tag
 /// and identify the mpsc channels.
@@ -47,15 +70,17 @@ pub trait TracingKeyStr {
 /// The sending half of the notifications channel(s).
 ///
 /// Used to send notifications from the BEEFY gadget side.
-#[derive(Clone)]
+///
+/// Cloning the sender is cheap, and all the clones dispatch to the same set of subscribers.
 pub struct NotificationSender<Payload: Clone> {
-	subscribers: SharedSenders<Payload>,
+	registry: SharedRegistry<Payload>,
 }
 
 impl<Payload: Clone> NotificationSender<Payload> {
-	/// The `subscribers` should be shared with a corresponding `NotificationStream`.
-	fn new(subscribers: SharedSenders<Payload>) -> Self {
-		Self { subscribers }
+	/// The `registry` should be shared with a corresponding `NotificationStream`.
+	fn new(registry: SharedRegistry<Payload>) -> Self {
+		registry.lock().add_sender();
+		Self { registry }
 	}
 
 	/// Send out a notification to all subscribers that a new payload is available for a
@@ -64,88 +89,538 @@ impl<Payload: Clone> NotificationSender<Payload> {
 		&self,
 		payload: impl FnOnce() -> Result<Payload, Error>,
 	) -> Result<(), Error> {
-		let mut subscribers = self.subscribers.lock();
+		self.dispatch(payload, || None)
+	}
+
+	/// Send out a notification to all subscribers, and wait for them to consume it.
+	///
+	/// Resolves with the number of subscribers which consumed the payload within the `timeout`.
+	/// This is heavier than [`Self::notify`], and only meant for the payloads the producer has to
+	/// know about the delivery of.
+	pub fn notify_with_ack(
+		&self,
+		payload: Payload,
+		timeout: Duration,
+	) -> impl Future<Output = usize> {
+		let mut acks = FuturesUnordered::new();
+		let _ = self.dispatch::<()>(
+			|| Ok(payload),
+			|| {
+				let (ack, acked) = oneshot::channel();
+				acks.push(acked);
+				Some(ack)
+			},
+		);
+
+		async move {
+			let mut timeout = futures_timer::Delay::new(timeout).fuse();
+			let mut acked = 0;
+			loop {
+				futures::select! {
+					ack = acks.next() => match ack {
+						Some(Ok(())) => acked += 1,
+						// dropped without being consumed
+						Some(Err(_)) => {},
+						None => break,
+					},
+					_ = timeout => break,
+				}
+			}
+			acked
+		}
+	}
+
+	fn dispatch<Error>(
+		&self,
+		payload: impl FnOnce() -> Result<Payload, Error>,
+		mut ack: impl FnMut() -> Option<oneshot::Sender<()>>,
+	) -> Result<(), Error> {
+		let mut registry = self.registry.lock();
 
 		// do an initial prune on closed subscriptions
-		subscribers.retain(|n| !n.is_closed());
+		registry.retain(|s| !s.sender.is_closed());
+
+		let span = tracing::debug_span!(
+			target: "notification",
+			"dispatch",
+			tracing_key = registry.tracing_key(),
+			subscribers = registry.subscribers().len(),
+		);
+		let _enter = span.enter();
 
-		if !subscribers.is_empty() {
+		if !registry.subscribers().is_empty() || registry.keeps_latest() {
 			let payload = payload()?;
-			subscribers.retain(|n| n.unbounded_send(payload.clone()).is_ok());
+			registry.update_latest(&payload);
+			registry.note_dispatched();
+			registry.retain(|s| {
+				if !s.accepts(&payload) {
+					return true
+				}
+				s.send(Envelope::new(payload.clone(), ack()))
+			});
 		}
 
 		Ok(())
 	}
+
+	/// Subscribe to the notifications dispatched through this sender, and the ones sharing its
+	/// channel, see [`NotificationStream::subscribe`].
+	pub fn subscribe(&self) -> NotificationReceiver<Payload> {
+		NotificationReceiver::new(&self.registry, None, None, Queue::unbounded()).0
+	}
+
+	/// Close all the subscriptions of the channel, so that their streams end on the next poll.
+	///
+	/// The payloads still queued for the subscriptions are discarded, and the `reason` is handed
+	/// to them, see [`NotificationReceiver::close_reason`]. Afterwards, notifying is a no-op
+	/// through any of the senders, and the new subscriptions end right away.
+	pub fn close_all(&self, reason: CloseReason) {
+		self.registry.lock().close_all(reason);
+	}
+
+	/// Label and high-water mark of the live subscription whose queue got the longest, see
+	/// [`NotificationReceiver::high_water_mark`].
+	///
+	/// Points at the subscriber most likely responsible for a memory growth, `None` if there are
+	/// no subscriptions.
+	pub fn highest_water_mark(&self) -> Option<(String, usize)> {
+		self.registry
+			.lock()
+			.subscribers()
+			.iter()
+			.max_by_key(|s| s.queue.high_water_mark())
+			.map(|s| (s.label.clone(), s.queue.high_water_mark()))
+	}
+
+	/// Wait until there are no subscribers left.
+	///
+	/// Lets the producer pause computing payloads nobody is going to receive. The returned
+	/// future resolves right away if there are no subscribers at the time of the call.
+	pub fn subscribers_gone(&self) -> impl Future<Output = ()> {
+		let signal = self.registry.lock().on_empty();
+		async move {
+			let _ = signal.await;
+		}
+	}
+}
+
+impl<Payload: Clone + Send + 'static> NotificationSender<Payload> {
+	/// Dispatch all the items of the `stream` through this channel.
+	///
+	/// The forwarding task is spawned with the `spawner`, and ends along with the `stream`, or
+	/// once the returned handle is dropped.
+	pub fn spawn_forward(
+		&self,
+		stream: impl Stream<Item = Payload> + Send + 'static,
+		spawner: &impl Spawn,
+	) -> Result<ForwardHandle, SpawnError> {
+		let sender = self.clone();
+		let forward = stream.for_each(move |payload| {
+			let _ = sender.notify::<()>(|| Ok(payload));
+			futures::future::ready(())
+		});
+		let (abort, registration) = AbortHandle::new_pair();
+		spawner.spawn(Abortable::new(forward, registration).map(|_| ()))?;
+		Ok(ForwardHandle { abort })
+	}
+}
+
+/// Handle to a task forwarding a stream into a notification channel, see
+/// [`NotificationSender::spawn_forward`].
+///
+/// The forwarding stops once the handle is dropped.
+pub struct ForwardHandle {
+	abort: AbortHandle,
+}
+
+impl Drop for ForwardHandle {
+	fn drop(&mut self) {
+		self.abort.abort();
+	}
 }
 
+impl<Payload: Clone> Clone for NotificationSender<Payload> {
+	fn clone(&self) -> Self {
+		Self::new(self.registry.clone())
+	}
+}
+
+impl<Payload: Clone> Drop for NotificationSender<Payload> {
+	fn drop(&mut self) {
+		self.registry.lock().remove_sender();
+	}
+}
+
+/// Error subscribing to a notification channel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SubscribeError {
+	/// All the senders of the channel are gone, or it got closed, see
+	/// [`NotificationSender::close_all`]. Nothing is ever going to be received.
+	Closed,
+}
+
+impl fmt::Display for SubscribeError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Self::Closed => write!(f, "the notification channel is closed"),
+		}
+	}
+}
+
+impl std::error::Error for SubscribeError {}
+
 /// The receiving half of the notifications channel.
 ///
-/// The `NotificationStream` entity stores the `SharedSenders` so it can be
+/// The `NotificationStream` entity stores the `SharedRegistry` so it can be
 /// used to add more subscriptions.
 #[derive(Clone)]
 pub struct NotificationStream<Payload: Clone, TK: TracingKeyStr> {
-	subscribers: SharedSenders<Payload>,
+	registry: SharedRegistry<Payload>,
 	_trace_key: PhantomData<TK>,
 }
 
 impl<Payload: Clone, TK: TracingKeyStr> NotificationStream<Payload, TK> {
 	/// Creates a new pair of receiver and sender of `Payload` notifications.
 	pub fn channel() -> (NotificationSender<Payload>, Self) {
-		let subscribers = Arc::new(Mutex::new(vec![]));
-		let receiver = NotificationStream::new(subscribers.clone());
-		let sender = NotificationSender::new(subscribers);
+		Self::with_metrics(None)
+	}
+
+	/// Creates `count` senders of `Payload` notifications sharing a single receiver.
+	///
+	/// Any of the senders dispatches to all the subscribers. This is the same as cloning the
+	/// sender returned by [`Self::channel`], but spells out that the channel is fed by several
+	/// independent producers.
+	pub fn channel_with_senders(count: usize) -> (Vec<NotificationSender<Payload>>, Self) {
+		let (sender, receiver) = Self::channel();
+		(vec![sender; count], receiver)
+	}
+
+	/// Creates a new pair of receiver and sender of `Payload` notifications, exposing the
+	/// number of live subscribers and of dispatched payloads as metrics named after
+	/// `TK::TRACING_KEY` in the given prometheus `registry`.
+	pub fn channel_with_metrics(
+		registry: &prometheus::Registry,
+	) -> Result<(NotificationSender<Payload>, Self), prometheus::Error> {
+		let metrics = Metrics::register(TK::TRACING_KEY, registry)?;
+		Ok(Self::with_metrics(Some(metrics)))
+	}
+
+	/// Creates a new pair of receiver and sender of `Payload` notifications, holding a single
+	/// value every new subscription receives first.
+	///
+	/// The value is the `initial` payload until a newer one gets dispatched, then the latest
+	/// dispatched one.
+	pub fn channel_with_initial(initial: Payload) -> (NotificationSender<Payload>, Self) {
+		let (sender, receiver) = Self::channel();
+		receiver.registry.lock().keep_latest(initial);
+		(sender, receiver)
+	}
+
+	fn with_metrics(metrics: Option<Metrics>) -> (NotificationSender<Payload>, Self) {
+		let registry = Arc::new(Mutex::new(Registry::new(TK::TRACING_KEY, metrics)));
+		let receiver = NotificationStream::new(registry.clone());
+		let sender = NotificationSender::new(registry);
 		(sender, receiver)
 	}
 
 	/// Create a new receiver of `Payload` notifications.
 	///
-	/// The `subscribers` should be shared with a corresponding `NotificationSender`.
-	fn new(subscribers: SharedSenders<Payload>) -> Self {
-		Self { subscribers, _trace_key: PhantomData }
+	/// The `registry` should be shared with a corresponding `NotificationSender`.
+	fn new(registry: SharedRegistry<Payload>) -> Self {
+		Self { registry, _trace_key: PhantomData }
 	}
 
 	/// Subscribe to a channel through which the generic payload can be received.
-	pub fn subscribe(&self) -> TracingUnboundedReceiver<Payload> {
-		let (sender, receiver) = tracing_unbounded(TK::TRACING_KEY);
-		self.subscribers.lock().push(sender);
+	pub fn subscribe(&self) -> NotificationReceiver<Payload> {
+		self.subscribe_with(None, None)
+	}
+
+	/// Subscribe to the channel, unless all of its senders are gone or it got closed.
+	///
+	/// Unlike [`Self::subscribe`], this tells a subscription which is never going to receive
+	/// anything apart from a live one.
+	pub fn try_subscribe(&self) -> Result<NotificationReceiver<Payload>, SubscribeError> {
+		if self.registry.lock().is_closed() {
+			return Err(SubscribeError::Closed)
+		}
+		Ok(self.subscribe())
+	}
+
+	/// Subscribe to the channel, queueing at most `capacity` payloads.
+	///
+	/// The payloads dispatched while `capacity` of them are waiting to be received are dropped
+	/// for this subscription, so that a subscriber falling behind does not grow its queue
+	/// indefinitely.
+	pub fn subscribe_bounded(&self, capacity: usize) -> NotificationReceiver<Payload> {
+		self.subscribe_with_backpressure(capacity, Backpressure::DropNewest)
+	}
+
+	/// Subscribe to the channel, queueing at most `capacity` payloads and applying the given
+	/// `backpressure` to the payloads dispatched while the queue is full.
+	pub fn subscribe_with_backpressure(
+		&self,
+		capacity: usize,
+		backpressure: Backpressure,
+	) -> NotificationReceiver<Payload> {
+		let queue = Queue::bounded(capacity, backpressure);
+		NotificationReceiver::new(&self.registry, None, None, queue).0
+	}
+
+	/// Subscribe to the channel, getting the payloads before the subscriptions of lower
+	/// `priority`.
+	///
+	/// The others subscribe with the lowest priority. As sending to an unbounded subscription
+	/// never blocks, the order only pays off when lower priority subscriptions can hold the
+	/// dispatch back, see [`Backpressure::Block`].
+	pub fn subscribe_with_priority(&self, priority: u8) -> NotificationReceiver<Payload> {
+		let (receiver, id) =
+			NotificationReceiver::new(&self.registry, None, None, Queue::unbounded());
+		self.registry.lock().set_priority(id, priority);
 		receiver
 	}
+
+	/// Subscribe to the channel, labelling the subscription with a human readable `name`.
+	///
+	/// The label is only used for diagnostics, see [`Self::subscriber_labels`].
+	pub fn subscribe_named(&self, name: impl Into<String>) -> NotificationReceiver<Payload> {
+		self.subscribe_with(Some(name.into()), None)
+	}
+
+	/// Subscribe to the channel, only receiving the payloads for which `filter` returns `true`.
+	///
+	/// The returned [`FilterHandle`] allows replacing the filter later on, without having to
+	/// re-subscribe.
+	pub fn subscribe_with_filter(
+		&self,
+		filter: impl Fn(&Payload) -> bool + Send + Sync + 'static,
+	) -> (NotificationReceiver<Payload>, FilterHandle<Payload>) {
+		let (receiver, id) = NotificationReceiver::new(
+			&self.registry,
+			None,
+			Some(Box::new(filter)),
+			Queue::unbounded(),
+		);
+		(receiver, FilterHandle::new(&self.registry, id))
+	}
+
+	/// Labels of all the live subscriptions.
+	pub fn subscriber_labels(&self) -> Vec<String> {
+		self.registry.lock().subscribers().iter().map(|s| s.label.clone()).collect()
+	}
+
+	/// Diagnostics of all the live subscriptions, in the order the payloads are dispatched to
+	/// them.
+	///
+	/// This is a snapshot taken at the time of the call.
+	pub fn subscribers(&self) -> impl Iterator<Item = SubscriberInfo> {
+		let infos: Vec<_> = self
+			.registry
+			.lock()
+			.subscribers()
+			.iter()
+			.map(|s| SubscriberInfo {
+				name: if s.named { Some(s.label.clone()) } else { None },
+				subscribed_at: s.subscribed_at,
+				queue_len: s.queue.len(),
+			})
+			.collect();
+		infos.into_iter()
+	}
+
+	/// Subscribe to the channel, transparently re-subscribing whenever the subscription is lost
+	/// while the channel is still open.
+	///
+	/// If a `backoff` is given, the stream waits that long before re-subscribing. The stream
+	/// ends along with the channel, i.e. once it got closed, see
+	/// [`NotificationSender::close_all`], or after the payloads dispatched before all of its
+	/// senders are gone.
+	pub fn resubscribing_stream(&self, backoff: Option<Duration>) -> impl Stream<Item = Payload> {
+		let stream = Self::new(self.registry.clone());
+		let receiver = self.subscribe();
+
+		futures::stream::unfold((stream, receiver), move |(stream, mut receiver)| async move {
+			loop {
+				if let Some(payload) = receiver.next().await {
+					return Some((payload, (stream, receiver)))
+				}
+				// the subscription ended along with the channel, rather than being lost
+				if stream.registry.lock().is_closed() {
+					return None
+				}
+				if let Some(backoff) = backoff {
+					futures_timer::Delay::new(backoff).await;
+				}
+				receiver = stream.subscribe();
+			}
+		})
+	}
+
+	fn subscribe_with(
+		&self,
+		label: Option<String>,
+		filter: Option<Filter<Payload>>,
+	) -> NotificationReceiver<Payload> {
+		NotificationReceiver::new(&self.registry, label, filter, Queue::unbounded()).0
+	}
 }
 
-#[cfg(test)]
-mod tests {
-	use super::*;
-	use futures::StreamExt;
+/// Diagnostics of a live subscription, see [`NotificationStream::subscribers`].
+#[derive(Debug, Clone)]
+pub struct SubscriberInfo {
+	/// The name given on subscribing, see [`NotificationStream::subscribe_named`].
+	pub name: Option<String>,
+	/// When the subscription was registered.
+	pub subscribed_at: Instant,
+	/// Number of the payloads waiting to be received.
+	pub queue_len: usize,
+}
+
+/// The receiving end of a single subscription.
+///
+/// The subscription is removed from the channel once the receiver is dropped. It ends once the
+/// channel got closed, or after the payloads dispatched before all of its senders are gone.
+///
+/// Receiving is cancellation safe: a payload is only taken out of the subscription by the poll
+/// returning it, so dropping a pending [`StreamExt::next`] or [`Self::next_or_closed`] future,
+/// e.g. the losing arm of a `select!`, never loses a payload.
+pub struct NotificationReceiver<Payload> {
+	receiver: TracingUnboundedReceiver<Envelope<Payload>>,
+	queue: Queue,
+	/// The payload taken out of the subscription by [`Self::peek`], not yet received.
+	peeked: Option<Envelope<Payload>>,
+	_guard: SubscriptionGuard<Payload>,
+}
+
+impl<Payload: Clone> NotificationReceiver<Payload> {
+	/// Register a new subscription in the `registry`, returning its receiver and id.
+	fn new(
+		registry: &SharedRegistry<Payload>,
+		label: Option<String>,
+		filter: Option<Filter<Payload>>,
+		queue: Queue,
+	) -> (Self, u64) {
+		let mut locked = registry.lock();
+		let (id, receiver) = locked.subscribe(label, filter, queue.clone());
+		locked.send_latest(id);
+		drop(locked);
+		let guard = SubscriptionGuard::new(registry, id);
+		(Self { receiver, queue, peeked: None, _guard: guard }, id)
+	}
+}
+
+impl<Payload> NotificationReceiver<Payload> {
+	/// Number of the payloads waiting to be received.
+	pub fn len(&self) -> usize {
+		self.queue.len() + usize::from(self.peeked.is_some())
+	}
+
+	/// Whether no payload is waiting to be received.
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
 
-	#[derive(Clone)]
-	pub struct DummyTracingKey;
-	impl TracingKeyStr for DummyTracingKey {
-		const TRACING_KEY: &'static str = "test_notification_stream";
+	/// Maximum number of the payloads ever waiting to be received at once.
+	pub fn high_water_mark(&self) -> usize {
+		self.queue.high_water_mark()
 	}
 
-	type StringStream = NotificationStream<String, DummyTracingKey>;
+	/// Maximum number of the payloads waiting to be received, `None` if unbounded.
+	pub fn capacity(&self) -> Option<usize> {
+		self.queue.capacity()
+	}
 
-	#[test]
-	fn notification_channel_simple() {
-		let (sender, stream) = StringStream::channel();
+	/// Why the channel closed the subscription, see [`NotificationSender::close_all`].
+	///
+	/// `None` while the subscription is live, and if it ended for another reason.
+	pub fn close_reason(&self) -> Option<CloseReason> {
+		self.queue.close_reason()
+	}
 
-		let test_payload = String::from("test payload");
-		let closure_payload = test_payload.clone();
+	/// Receive the next payload, or learn the subscription is closed.
+	///
+	/// Same as [`StreamExt::next`], but reads better in `select!` arms.
+	pub fn next_or_closed(&mut self) -> impl Future<Output = Recv<Payload>> + '_ {
+		self.next().map(|payload| match payload {
+			Some(payload) => Recv::Payload(payload),
+			None => Recv::Closed,
+		})
+	}
 
-		// Create a future to receive a single notification
-		// from the stream and verify its payload.
-		let future = stream.subscribe().take(1).for_each(move |payload| {
-			let test_payload = closure_payload.clone();
-			async move {
-				assert_eq!(payload, test_payload);
+	/// Receive the payloads of both this and the `other` subscription through a single stream.
+	///
+	/// Both subscriptions are kept for as long as the merged stream lives. It goes on with the
+	/// remaining subscription once one of them ends, and ends with the last one.
+	pub fn merge<Other>(
+		self,
+		other: NotificationReceiver<Other>,
+	) -> impl FusedStream<Item = Either<Payload, Other>> {
+		futures::stream::select(self.map(Either::Left), other.map(Either::Right))
+	}
+
+	/// Look at the next payload, if already dispatched, without receiving it.
+	///
+	/// The peeked payload is buffered by the receiver until received, so a bounded subscription
+	/// can hold one payload more than its capacity meanwhile.
+	pub fn peek(&mut self) -> Option<&Payload> {
+		if self.peeked.is_none() && !self.queue.is_closed() {
+			while let Ok(Some(envelope)) = self.receiver.try_next() {
+				if self.queue.popped() {
+					self.peeked = Some(envelope);
+					break
+				}
 			}
-		});
+		}
+		self.peeked.as_ref().map(Envelope::payload)
+	}
+}
 
-		// Send notification.
-		let r: std::result::Result<(), ()> = sender.notify(|| Ok(test_payload));
-		r.unwrap();
+/// Outcome of [`NotificationReceiver::next_or_closed`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Recv<Payload> {
+	/// The next payload of the subscription.
+	Payload(Payload),
+	/// The subscription is closed, nothing is going to be received anymore.
+	Closed,
+}
+
+impl<Payload> Stream for NotificationReceiver<Payload> {
+	type Item = Payload;
+
+	fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Payload>> {
+		// Only the discarded payloads are taken out without being returned, which keeps the
+		// receiver cancellation safe.
+		loop {
+			if self.queue.is_closed() {
+				return Poll::Ready(None)
+			}
+			if let Some(envelope) = self.peeked.take() {
+				return Poll::Ready(Some(envelope.open()))
+			}
+			match futures::ready!(self.receiver.poll_next_unpin(cx)) {
+				Some(envelope) =>
+					if self.queue.popped() {
+						return Poll::Ready(Some(envelope.open()))
+					},
+				None => return Poll::Ready(None),
+			}
+		}
+	}
+}
+
+// The receiver is never pinned structurally, the peeked payload included.
+impl<Payload> Unpin for NotificationReceiver<Payload> {}
+
+impl<Payload> Drop for NotificationReceiver<Payload> {
+	fn drop(&mut self) {
+		// Release the senders blocked on a full queue before unsubscribing, as they hold the
+		// registry lock meanwhile.
+		self.receiver.close();
+		self.queue.wake();
+	}
+}
 
-		// Run receiver future.
-		tokio_test::block_on(future);
+impl<Payload> FusedStream for NotificationReceiver<Payload> {
+	fn is_terminated(&self) -> bool {
+		self.queue.is_closed() || (self.peeked.is_none() && self.receiver.is_terminated())
 	}
 }
@@ -0,0 +1,110 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2022 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Sender dispatching the payloads in batches.
+
+use std::{
+	mem,
+	sync::{Arc, Weak},
+	time::Duration,
+};
+
+use futures::task::{Spawn, SpawnError, SpawnExt};
+use parking_lot::Mutex;
+
+use super::NotificationSender;
+
+/// Sender accumulating the payloads, and dispatching them in batches.
+///
+/// A batch is dispatched once it holds `max_size` payloads, and at least every `interval`
+/// otherwise. The payloads still pending are dispatched when the sender is dropped.
+pub struct BatchingSender<Payload: Clone> {
+	inner: Arc<Inner<Payload>>,
+}
+
+struct Inner<Payload: Clone> {
+	sender: NotificationSender<Vec<Payload>>,
+	pending: Mutex<Vec<Payload>>,
+	max_size: usize,
+}
+
+impl<Payload: Clone> Inner<Payload> {
+	fn flush(&self) {
+		self.dispatch(&mut self.pending.lock());
+	}
+
+	/// Dispatch the `pending` batch, unless empty.
+	///
+	/// The pending payloads stay locked until dispatched, so that the flushes racing each other
+	/// dispatch their batches in order.
+	fn dispatch(&self, pending: &mut Vec<Payload>) {
+		if !pending.is_empty() {
+			let batch = mem::take(pending);
+			let _ = self.sender.notify::<()>(|| Ok(batch));
+		}
+	}
+}
+
+impl<Payload: Clone + Send + 'static> BatchingSender<Payload> {
+	/// Batch the payloads dispatched through the `sender`.
+	///
+	/// The task flushing the batches every `interval` is spawned with the `spawner`, and ends
+	/// along with the returned sender.
+	pub fn new(
+		sender: NotificationSender<Vec<Payload>>,
+		max_size: usize,
+		interval: Duration,
+		spawner: &impl Spawn,
+	) -> Result<Self, SpawnError> {
+		let inner = Arc::new(Inner { sender, pending: Mutex::new(Vec::new()), max_size });
+		spawner.spawn(flush_periodically(Arc::downgrade(&inner), interval))?;
+		Ok(Self { inner })
+	}
+}
+
+impl<Payload: Clone> BatchingSender<Payload> {
+	/// Add the `payload` to the current batch, dispatching it if full.
+	pub fn notify(&self, payload: Payload) {
+		let mut pending = self.inner.pending.lock();
+		pending.push(payload);
+		if pending.len() >= self.inner.max_size {
+			self.inner.dispatch(&mut pending);
+		}
+	}
+
+	/// Dispatch the current batch right away, unless empty.
+	pub fn flush(&self) {
+		self.inner.flush();
+	}
+}
+
+impl<Payload: Clone> Drop for BatchingSender<Payload> {
+	fn drop(&mut self) {
+		self.inner.flush();
+	}
+}
+
+async fn flush_periodically<Payload: Clone>(inner: Weak<Inner<Payload>>, interval: Duration) {
+	loop {
+		futures_timer::Delay::new(interval).await;
+		match inner.upgrade() {
+			Some(inner) => inner.flush(),
+			None => return,
+		}
+	}
+}
@@ -0,0 +1,66 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2022 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Notification channel carrying several kinds of events.
+
+use super::{NotificationReceiver, NotificationSender, NotificationStream, TracingKeyStr};
+
+/// Payload of a [`MultiplexedStream`], telling its kind apart.
+///
+/// Usually implemented by an enum of the events carried by the channel, with one kind per
+/// variant.
+pub trait MultiplexedPayload: Clone {
+	/// Kind of the payloads, subscribers opt into.
+	type Kind: Copy + Eq + Send + Sync + 'static;
+
+	/// The kind of this payload.
+	fn kind(&self) -> Self::Kind;
+}
+
+/// The receiving half of a notification channel carrying several kinds of `Payload`.
+///
+/// Every subscriber only receives the kinds of payloads it asked for, sparing separate channels
+/// for the related events.
+#[derive(Clone)]
+pub struct MultiplexedStream<Payload: MultiplexedPayload, TK: TracingKeyStr> {
+	stream: NotificationStream<Payload, TK>,
+}
+
+impl<Payload: MultiplexedPayload, TK: TracingKeyStr> MultiplexedStream<Payload, TK> {
+	/// Creates a new pair of receiver and sender of multiplexed `Payload` notifications.
+	pub fn channel() -> (NotificationSender<Payload>, Self) {
+		let (sender, stream) = NotificationStream::channel();
+		(sender, Self { stream })
+	}
+
+	/// Subscribe to the payloads of the given `kinds`.
+	pub fn subscribe(
+		&self,
+		kinds: impl IntoIterator<Item = Payload::Kind>,
+	) -> NotificationReceiver<Payload> {
+		let kinds: Vec<_> = kinds.into_iter().collect();
+		self.stream
+			.subscribe_with_filter(move |payload| kinds.contains(&payload.kind()))
+			.0
+	}
+
+	/// Subscribe to the payloads of all kinds.
+	pub fn subscribe_all(&self) -> NotificationReceiver<Payload> {
+		self.stream.subscribe()
+	}
+}
@@ -0,0 +1,556 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2021-2022 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Book-keeping of the subscriptions shared by both halves of the notification channel.
+
+use std::{
+	cmp::Reverse,
+	sync::{
+		atomic::{AtomicBool, AtomicUsize, Ordering},
+		Arc, Weak,
+	},
+	time::Instant,
+};
+
+use futures::channel::oneshot;
+use parking_lot::{Condvar, Mutex};
+use prometheus::{Error as PrometheusError, IntCounter, IntGauge, Registry as PrometheusRegistry};
+
+use crate::mpsc::{tracing_unbounded, TracingUnboundedReceiver, TracingUnboundedSender};
+
+/// Registry shared between the senders and the receivers of a notification channel.
+pub(super) type SharedRegistry<Payload> = Arc<Mutex<Registry<Payload>>>;
+
+/// Predicate deciding whether a payload should be delivered to a subscription.
+pub(super) type Filter<Payload> = Box<dyn Fn(&Payload) -> bool + Send + Sync>;
+
+/// A payload on its way to a subscriber.
+pub(super) struct Envelope<Payload> {
+	payload: Payload,
+	ack: Option<oneshot::Sender<()>>,
+}
+
+impl<Payload> Envelope<Payload> {
+	/// Wrap the `payload`, to be acknowledged through `ack` once consumed, if given.
+	pub(super) fn new(payload: Payload, ack: Option<oneshot::Sender<()>>) -> Self {
+		Self { payload, ack }
+	}
+
+	/// The wrapped payload.
+	pub(super) fn payload(&self) -> &Payload {
+		&self.payload
+	}
+
+	/// Take the payload out, acknowledging its consumption if requested.
+	pub(super) fn open(self) -> Payload {
+		if let Some(ack) = self.ack {
+			let _ = ack.send(());
+		}
+		self.payload
+	}
+}
+
+/// Sending endpoint of a single subscription.
+pub(super) struct Subscriber<Payload> {
+	id: u64,
+	pub(super) label: String,
+	/// Whether the label was given on subscribing, rather than generated.
+	pub(super) named: bool,
+	pub(super) subscribed_at: Instant,
+	/// Subscriptions of higher priority get the payloads first.
+	priority: u8,
+	pub(super) filter: Option<Filter<Payload>>,
+	pub(super) sender: TracingUnboundedSender<Envelope<Payload>>,
+	pub(super) queue: Queue,
+}
+
+impl<Payload> Subscriber<Payload> {
+	/// Whether the `payload` passes the subscription's filter.
+	pub(super) fn accepts(&self, payload: &Payload) -> bool {
+		match &self.filter {
+			Some(filter) => filter(payload),
+			None => true,
+		}
+	}
+
+	/// Send the `envelope` to the subscription, applying its backpressure if its queue is full.
+	///
+	/// Returns `false` if the subscription is gone.
+	pub(super) fn send(&self, envelope: Envelope<Payload>) -> bool {
+		if self.queue.is_full() {
+			match self.queue.backpressure {
+				Backpressure::DropNewest => {
+					tracing::trace!(
+						target: "notification",
+						subscriber = self.label.as_str(),
+						"queue full, payload dropped",
+					);
+					return true
+				},
+				Backpressure::DropOldest => {
+					tracing::trace!(
+						target: "notification",
+						subscriber = self.label.as_str(),
+						"queue full, oldest payload dropped",
+					);
+					self.queue.skip_oldest();
+				},
+				Backpressure::Block =>
+					if !self.queue.wait_for_room(|| self.sender.is_closed()) {
+						return false
+					},
+			}
+		}
+		self.queue.pushed();
+		if self.sender.unbounded_send(envelope).is_err() {
+			self.queue.popped();
+			return false
+		}
+		tracing::trace!(target: "notification", subscriber = self.label.as_str(), "payload delivered");
+		true
+	}
+}
+
+/// What to do with a payload dispatched to a bounded subscription whose queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backpressure {
+	/// Drop the dispatched payload.
+	DropNewest,
+	/// Drop the oldest queued payload to make room for the dispatched one.
+	///
+	/// The dropped payload is only discarded once the receiver gets to it, so it keeps taking
+	/// memory until then.
+	DropOldest,
+	/// Block the dispatching thread until the receiver makes room, or is dropped.
+	///
+	/// The channel stays locked meanwhile, so its other senders get blocked as well, and so does
+	/// subscribing. Never use it if the receiver is polled by the thread doing the sending.
+	Block,
+}
+
+/// Why a notification channel got closed, see
+/// [`NotificationSender::close_all`](super::NotificationSender::close_all).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CloseReason {
+	/// The producer shut down cleanly, nothing more is going to be dispatched.
+	Shutdown,
+	/// The producer broke down, and may come back with another channel.
+	Failure(String),
+}
+
+/// Book-keeping of the payloads queued for a subscription, shared with its receiver.
+#[derive(Clone)]
+pub(super) struct Queue {
+	state: Arc<QueueState>,
+	capacity: Option<usize>,
+	backpressure: Backpressure,
+}
+
+#[derive(Default)]
+struct QueueState {
+	/// Number of the payloads in the channel, including the ones to discard.
+	len: AtomicUsize,
+	/// Number of the oldest payloads in the channel to discard.
+	skip: AtomicUsize,
+	/// Maximum number of the queued payloads ever reached.
+	high_water: AtomicUsize,
+	/// Whether the subscription got closed by the channel, discarding all the queued payloads.
+	closed: AtomicBool,
+	close_reason: Mutex<Option<CloseReason>>,
+	lock: Mutex<()>,
+	room: Condvar,
+}
+
+impl Queue {
+	/// Queue of an unbounded subscription.
+	pub(super) fn unbounded() -> Self {
+		Self { state: Default::default(), capacity: None, backpressure: Backpressure::DropNewest }
+	}
+
+	/// Queue holding at most `capacity` payloads.
+	pub(super) fn bounded(capacity: usize, backpressure: Backpressure) -> Self {
+		Self { state: Default::default(), capacity: Some(capacity), backpressure }
+	}
+
+	/// Number of the queued payloads.
+	pub(super) fn len(&self) -> usize {
+		let len = self.state.len.load(Ordering::Acquire);
+		len.saturating_sub(self.state.skip.load(Ordering::Acquire))
+	}
+
+	/// Maximum number of the queued payloads ever reached.
+	pub(super) fn high_water_mark(&self) -> usize {
+		self.state.high_water.load(Ordering::Acquire)
+	}
+
+	/// Maximum number of queued payloads, `None` if unbounded.
+	pub(super) fn capacity(&self) -> Option<usize> {
+		self.capacity
+	}
+
+	/// Note a payload was taken out of the channel.
+	///
+	/// Returns `false` if the payload has to be discarded, see [`Backpressure::DropOldest`].
+	pub(super) fn popped(&self) -> bool {
+		self.state.len.fetch_sub(1, Ordering::AcqRel);
+		let skipped = self
+			.state
+			.skip
+			.fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| n.checked_sub(1));
+		self.wake();
+		skipped.is_err()
+	}
+
+	/// Whether the subscription got closed by the channel.
+	pub(super) fn is_closed(&self) -> bool {
+		self.state.closed.load(Ordering::Acquire)
+	}
+
+	/// Why the subscription got closed by the channel, if it did.
+	pub(super) fn close_reason(&self) -> Option<CloseReason> {
+		self.state.close_reason.lock().clone()
+	}
+
+	/// Wake the senders waiting for room, see [`Backpressure::Block`].
+	pub(super) fn wake(&self) {
+		let _lock = self.state.lock.lock();
+		self.state.room.notify_all();
+	}
+
+	fn pushed(&self) {
+		self.state.len.fetch_add(1, Ordering::AcqRel);
+		self.state.high_water.fetch_max(self.len(), Ordering::AcqRel);
+	}
+
+	fn close(&self, reason: CloseReason) {
+		*self.state.close_reason.lock() = Some(reason);
+		self.state.closed.store(true, Ordering::Release);
+	}
+
+	fn skip_oldest(&self) {
+		self.state.skip.fetch_add(1, Ordering::AcqRel);
+	}
+
+	/// Wait until the queue is not full, unless the subscription gets `closed` meanwhile.
+	///
+	/// Returns `false` if it did.
+	fn wait_for_room(&self, closed: impl Fn() -> bool) -> bool {
+		let mut lock = self.state.lock.lock();
+		while self.is_full() {
+			if closed() {
+				return false
+			}
+			self.state.room.wait(&mut lock);
+		}
+		true
+	}
+
+	fn is_full(&self) -> bool {
+		match self.capacity {
+			Some(capacity) => self.len() >= capacity,
+			None => false,
+		}
+	}
+}
+
+/// Prometheus metrics of a notification channel.
+pub(super) struct Metrics {
+	subscribers: IntGauge,
+	dispatched: IntCounter,
+}
+
+impl Metrics {
+	/// Register the metrics of the channel tagged with `tracing_key`.
+	pub(super) fn register(
+		tracing_key: &'static str,
+		registry: &PrometheusRegistry,
+	) -> Result<Self, PrometheusError> {
+		let subscribers = IntGauge::new(
+			format!("substrate_{}_subscribers", metric_name(tracing_key)),
+			format!("Number of live subscribers of the {} notification channel", tracing_key),
+		)?;
+		registry.register(Box::new(subscribers.clone()))?;
+		let dispatched = IntCounter::new(
+			format!("substrate_{}_dispatched_total", metric_name(tracing_key)),
+			format!(
+				"Number of payloads dispatched through the {} notification channel",
+				tracing_key
+			),
+		)?;
+		registry.register(Box::new(dispatched.clone()))?;
+		Ok(Self { subscribers, dispatched })
+	}
+}
+
+/// Turn the "tracing key" into a valid prometheus metric name fragment.
+fn metric_name(tracing_key: &str) -> String {
+	tracing_key
+		.chars()
+		.map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+		.collect()
+}
+
+/// The set of live subscriptions of a notification channel.
+pub(super) struct Registry<Payload> {
+	tracing_key: &'static str,
+	next_id: u64,
+	subscribers: Vec<Subscriber<Payload>>,
+	on_empty: Vec<oneshot::Sender<()>>,
+	metrics: Option<Metrics>,
+	senders: usize,
+	closed: Option<CloseReason>,
+	latest: Option<Payload>,
+}
+
+impl<Payload> Registry<Payload> {
+	/// Create an empty registry, whose channels will be tagged with `tracing_key`.
+	pub(super) fn new(tracing_key: &'static str, metrics: Option<Metrics>) -> Self {
+		Self {
+			tracing_key,
+			next_id: 0,
+			subscribers: Vec::new(),
+			on_empty: Vec::new(),
+			metrics,
+			senders: 0,
+			closed: None,
+			latest: None,
+		}
+	}
+
+	/// The "tracing key" of the channel.
+	pub(super) fn tracing_key(&self) -> &'static str {
+		self.tracing_key
+	}
+
+	/// Note a new sender of the channel.
+	pub(super) fn add_sender(&mut self) {
+		self.senders += 1;
+	}
+
+	/// Note a sender of the channel is gone.
+	///
+	/// Once the last one is, the subscriptions end after receiving the payloads queued for them.
+	pub(super) fn remove_sender(&mut self) {
+		self.senders -= 1;
+		if self.senders == 0 {
+			for subscriber in &self.subscribers {
+				subscriber.sender.close_channel();
+			}
+			self.retain(|_| false);
+		}
+	}
+
+	/// Whether nothing is going to be dispatched through the channel anymore, either because
+	/// all its senders are gone or because it got closed.
+	pub(super) fn is_closed(&self) -> bool {
+		self.closed.is_some() || self.senders == 0
+	}
+
+	/// Close all the subscriptions for the given `reason`, discarding their queued payloads, and
+	/// refuse new ones.
+	pub(super) fn close_all(&mut self, reason: CloseReason) {
+		for subscriber in &self.subscribers {
+			subscriber.queue.close(reason.clone());
+			subscriber.sender.close_channel();
+		}
+		self.retain(|_| false);
+		self.closed = Some(reason);
+	}
+
+	/// Hand the `initial` payload, and then the latest dispatched one, to the new subscriptions.
+	pub(super) fn keep_latest(&mut self, initial: Payload) {
+		self.latest = Some(initial);
+	}
+
+	/// Whether the new subscriptions get the latest payload, see [`Self::keep_latest`].
+	pub(super) fn keeps_latest(&self) -> bool {
+		self.latest.is_some()
+	}
+
+	/// Live subscriptions, in the order the payloads are dispatched to them.
+	pub(super) fn subscribers(&self) -> &[Subscriber<Payload>] {
+		&self.subscribers
+	}
+
+	/// Register a new subscription, labelled for diagnostics, only receiving the payloads
+	/// passing the `filter`, if any, and queueing them in the `queue`.
+	///
+	/// Unnamed subscriptions are labelled after the channel's "tracing key" and their id.
+	///
+	/// Returns the id of the subscription along with its receiving end.
+	pub(super) fn subscribe(
+		&mut self,
+		label: Option<String>,
+		filter: Option<Filter<Payload>>,
+		queue: Queue,
+	) -> (u64, TracingUnboundedReceiver<Envelope<Payload>>) {
+		let (sender, receiver) = tracing_unbounded(self.tracing_key);
+		let id = self.next_id;
+		self.next_id += 1;
+		if let Some(reason) = &self.closed {
+			queue.close(reason.clone());
+			return (id, receiver)
+		}
+		if self.senders == 0 {
+			// dropping the sending end ends the subscription right away
+			return (id, receiver)
+		}
+		let named = label.is_some();
+		let label = label.unwrap_or_else(|| format!("{}#{}", self.tracing_key, id));
+		self.subscribers.push(Subscriber {
+			id,
+			label,
+			named,
+			subscribed_at: Instant::now(),
+			priority: 0,
+			filter,
+			sender,
+			queue,
+		});
+		self.update_metrics();
+		(id, receiver)
+	}
+
+	/// Set the priority of the subscription with the given id.
+	///
+	/// The subscriptions are kept sorted by descending priority, the ones of the same priority
+	/// in the order they subscribed.
+	pub(super) fn set_priority(&mut self, id: u64, priority: u8) {
+		if let Some(subscriber) = self.subscribers.iter_mut().find(|s| s.id == id) {
+			subscriber.priority = priority;
+			self.subscribers.sort_by_key(|s| Reverse(s.priority));
+		}
+	}
+
+	/// Replace the filter of the subscription with the given id.
+	pub(super) fn set_filter(&mut self, id: u64, filter: Filter<Payload>) {
+		if let Some(subscriber) = self.subscribers.iter_mut().find(|s| s.id == id) {
+			subscriber.filter = Some(filter);
+		}
+	}
+
+	/// Remove the subscription with the given id.
+	pub(super) fn unsubscribe(&mut self, id: u64) {
+		self.retain(|s| s.id != id);
+	}
+
+	/// Keep only the subscriptions matching the predicate.
+	///
+	/// Fires the `on_empty` signals if the last subscription goes away.
+	pub(super) fn retain(&mut self, f: impl FnMut(&Subscriber<Payload>) -> bool) {
+		let had_subscribers = !self.subscribers.is_empty();
+		self.subscribers.retain(f);
+		self.update_metrics();
+
+		if had_subscribers && self.subscribers.is_empty() {
+			for signal in self.on_empty.drain(..) {
+				let _ = signal.send(());
+			}
+		}
+	}
+
+	/// Get signalled once there are no subscriptions left.
+	///
+	/// The signal fires right away if there are none at the moment.
+	pub(super) fn on_empty(&mut self) -> oneshot::Receiver<()> {
+		let (tx, rx) = oneshot::channel();
+		if self.subscribers.is_empty() {
+			let _ = tx.send(());
+		} else {
+			self.on_empty.push(tx);
+		}
+		rx
+	}
+
+	/// Note a payload was dispatched to the subscriptions.
+	pub(super) fn note_dispatched(&self) {
+		if let Some(metrics) = &self.metrics {
+			metrics.dispatched.inc();
+		}
+	}
+
+	fn update_metrics(&self) {
+		if let Some(metrics) = &self.metrics {
+			metrics.subscribers.set(self.subscribers.len() as i64);
+		}
+	}
+}
+
+impl<Payload: Clone> Registry<Payload> {
+	/// Remember the dispatched `payload` as the latest one, if the registry keeps it.
+	pub(super) fn update_latest(&mut self, payload: &Payload) {
+		if let Some(latest) = &mut self.latest {
+			*latest = payload.clone();
+		}
+	}
+
+	/// Send the latest payload, if kept, to the subscription with the given id.
+	pub(super) fn send_latest(&self, id: u64) {
+		let latest = match &self.latest {
+			Some(latest) => latest,
+			None => return,
+		};
+		if let Some(subscriber) = self.subscribers.iter().find(|s| s.id == id) {
+			if subscriber.accepts(latest) {
+				subscriber.send(Envelope::new(latest.clone(), None));
+			}
+		}
+	}
+}
+
+/// Removes the subscription from the registry when dropped.
+pub(super) struct SubscriptionGuard<Payload> {
+	registry: Weak<Mutex<Registry<Payload>>>,
+	id: u64,
+}
+
+impl<Payload> SubscriptionGuard<Payload> {
+	pub(super) fn new(registry: &SharedRegistry<Payload>, id: u64) -> Self {
+		Self { registry: Arc::downgrade(registry), id }
+	}
+}
+
+impl<Payload> Drop for SubscriptionGuard<Payload> {
+	fn drop(&mut self) {
+		if let Some(registry) = self.registry.upgrade() {
+			registry.lock().unsubscribe(self.id);
+		}
+	}
+}
+
+/// Handle to the filter of a subscription, see
+/// [`NotificationStream::subscribe_with_filter`](super::NotificationStream::subscribe_with_filter).
+pub struct FilterHandle<Payload> {
+	registry: Weak<Mutex<Registry<Payload>>>,
+	id: u64,
+}
+
+impl<Payload> FilterHandle<Payload> {
+	pub(super) fn new(registry: &SharedRegistry<Payload>, id: u64) -> Self {
+		Self { registry: Arc::downgrade(registry), id }
+	}
+
+	/// Replace the predicate deciding which payloads are delivered to the subscription.
+	///
+	/// Takes effect starting with the next dispatched payload.
+	pub fn set_filter(&self, filter: impl Fn(&Payload) -> bool + Send + Sync + 'static) {
+		if let Some(registry) = self.registry.upgrade() {
+			registry.lock().set_filter(self.id, Box::new(filter));
+		}
+	}
+}
@@ -0,0 +1,821 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2021-2022 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use super::*;
+use futures::{FutureExt, StreamExt};
+
+#[derive(Clone)]
+pub struct DummyTracingKey;
+impl TracingKeyStr for DummyTracingKey {
+	const TRACING_KEY: &'static str = "test_notification_stream";
+}
+
+type StringStream = NotificationStream<String, DummyTracingKey>;
+type NumberStream = NotificationStream<u64, DummyTracingKey>;
+
+#[test]
+fn notification_channel_simple() {
+	let (sender, stream) = StringStream::channel();
+
+	let test_payload = String::from("test payload");
+	let closure_payload = test_payload.clone();
+
+	// Create a future to receive a single notification
+	// from the stream and verify its payload.
+	let future = stream.subscribe().take(1).for_each(move |payload| {
+		let test_payload = closure_payload.clone();
+		async move {
+			assert_eq!(payload, test_payload);
+		}
+	});
+
+	// Send notification.
+	let r: std::result::Result<(), ()> = sender.notify(|| Ok(test_payload));
+	r.unwrap();
+
+	// Run receiver future.
+	tokio_test::block_on(future);
+}
+
+#[test]
+fn subscriptions_can_be_forwarded_from_spawned_tasks() {
+	// the RPC handlers of GRANDPA and BEEFY forward their subscriptions from spawned tasks
+	fn assert_forwardable<S: Stream + Send + Unpin + 'static>(_: &S) {}
+
+	let (sender, stream) = StringStream::channel();
+	let receiver = stream.subscribe();
+	assert_forwardable(&receiver);
+
+	let received =
+		std::thread::spawn(move || tokio_test::block_on(receiver.take(1).collect::<Vec<_>>()));
+	sender.notify::<()>(|| Ok("test payload".into())).unwrap();
+	assert_eq!(received.join().unwrap(), vec![String::from("test payload")]);
+}
+
+#[test]
+fn notify_is_traced() {
+	use std::fmt;
+	use tracing::{
+		field::{Field, Visit},
+		span::{Attributes, Id, Record},
+	};
+
+	#[derive(Default)]
+	struct Recorded {
+		spans: Vec<(Option<String>, Option<String>)>,
+		events: usize,
+	}
+
+	#[derive(Clone, Default)]
+	struct TestSubscriber(Arc<Mutex<Recorded>>);
+
+	struct FieldConsumer(&'static str, Option<String>);
+	impl Visit for FieldConsumer {
+		fn record_str(&mut self, field: &Field, value: &str) {
+			if field.name() == self.0 {
+				self.1 = Some(value.to_owned())
+			}
+		}
+
+		fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+			if field.name() == self.0 {
+				self.1 = Some(format!("{:?}", value))
+			}
+		}
+	}
+
+	impl tracing::Subscriber for TestSubscriber {
+		fn enabled(&self, _: &tracing::Metadata) -> bool {
+			true
+		}
+
+		fn new_span(&self, span: &Attributes) -> Id {
+			let mut recorded = self.0.lock();
+			let mut key = FieldConsumer("tracing_key", None);
+			let mut count = FieldConsumer("subscribers", None);
+			span.record(&mut key);
+			span.record(&mut count);
+			recorded.spans.push((key.1, count.1));
+			Id::from_u64(recorded.spans.len() as u64)
+		}
+
+		fn record(&self, _: &Id, _: &Record) {}
+
+		fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+		fn event(&self, _: &tracing::Event) {
+			self.0.lock().events += 1;
+		}
+
+		fn enter(&self, _: &Id) {}
+
+		fn exit(&self, _: &Id) {}
+	}
+
+	let subscriber = TestSubscriber::default();
+	let _guard = tracing::subscriber::set_default(subscriber.clone());
+
+	let (sender, stream) = StringStream::channel();
+	let _first = stream.subscribe();
+	let _second = stream.subscribe();
+
+	let r: std::result::Result<(), ()> = sender.notify(|| Ok(String::from("test payload")));
+	r.unwrap();
+
+	let recorded = subscriber.0.lock();
+	assert_eq!(
+		recorded.spans,
+		vec![(Some(DummyTracingKey::TRACING_KEY.to_owned()), Some("2".to_owned()))]
+	);
+	assert_eq!(recorded.events, 2);
+}
+
+#[test]
+fn subscribers_gone_fires_once_the_last_receiver_drops() {
+	let (sender, stream) = StringStream::channel();
+
+	let first = stream.subscribe();
+	let second = stream.subscribe();
+
+	let mut gone = Box::pin(sender.subscribers_gone());
+	assert!((&mut gone).now_or_never().is_none());
+
+	drop(first);
+	assert!((&mut gone).now_or_never().is_none());
+
+	drop(second);
+	assert!(gone.now_or_never().is_some());
+
+	// nobody is subscribed anymore, so a new signal fires right away
+	assert!(sender.subscribers_gone().now_or_never().is_some());
+}
+
+#[test]
+fn subscriber_labels_follow_live_subscriptions() {
+	let (_sender, stream) = StringStream::channel();
+
+	let rpc = stream.subscribe_named("rpc");
+	let _gadget = stream.subscribe_named("gadget");
+	let _unnamed = stream.subscribe();
+
+	assert_eq!(
+		stream.subscriber_labels(),
+		vec!["rpc".to_owned(), "gadget".to_owned(), "test_notification_stream#2".to_owned()],
+	);
+
+	drop(rpc);
+	assert_eq!(
+		stream.subscriber_labels(),
+		vec!["gadget".to_owned(), "test_notification_stream#2".to_owned()],
+	);
+}
+
+#[test]
+fn subscribers_gauge_tracks_live_subscriptions() {
+	fn subscribers(registry: &prometheus::Registry) -> f64 {
+		let families = registry.gather();
+		let family = families
+			.iter()
+			.find(|f| f.get_name() == "substrate_test_notification_stream_subscribers")
+			.expect("the gauge is registered");
+		family.get_metric()[0].get_gauge().get_value()
+	}
+
+	let registry = prometheus::Registry::new();
+	let (_sender, stream) = StringStream::channel_with_metrics(&registry).unwrap();
+	assert_eq!(subscribers(&registry), 0.0);
+
+	let first = stream.subscribe();
+	let second = stream.subscribe();
+	assert_eq!(subscribers(&registry), 2.0);
+
+	drop(first);
+	assert_eq!(subscribers(&registry), 1.0);
+
+	drop(second);
+	assert_eq!(subscribers(&registry), 0.0);
+}
+
+#[test]
+fn resubscribing_stream_keeps_delivering() {
+	let (sender, stream) = StringStream::channel();
+	let mut resubscribing = Box::pin(stream.resubscribing_stream(None));
+	let notify = |payload: &str| {
+		let r: std::result::Result<(), ()> = sender.notify(|| Ok(payload.to_owned()));
+		r.unwrap();
+	};
+
+	notify("first");
+	assert_eq!(futures::executor::block_on(resubscribing.next()), Some("first".to_owned()));
+
+	// lose the subscription of the stream, the first one of the channel, which stays open
+	stream.registry.lock().unsubscribe(0);
+	assert!(stream.subscriber_labels().is_empty());
+	assert!(resubscribing.next().now_or_never().is_none());
+	assert_eq!(stream.subscriber_labels(), vec!["test_notification_stream#1".to_owned()]);
+
+	notify("second");
+	assert_eq!(futures::executor::block_on(resubscribing.next()), Some("second".to_owned()));
+}
+
+#[test]
+fn resubscribing_stream_ends_once_all_senders_are_gone() {
+	let (sender, stream) = StringStream::channel();
+	let mut resubscribing = Box::pin(stream.resubscribing_stream(None));
+
+	let r: std::result::Result<(), ()> = sender.notify(|| Ok("last".to_owned()));
+	r.unwrap();
+	drop(sender);
+	assert_eq!(futures::executor::block_on(resubscribing.next()), Some("last".to_owned()));
+	assert_eq!(resubscribing.next().now_or_never(), Some(None));
+	assert!(stream.subscriber_labels().is_empty());
+}
+
+#[test]
+fn resubscribing_stream_ends_once_closed() {
+	for backoff in [None, Some(Duration::from_secs(3600))] {
+		let (sender, stream) = StringStream::channel();
+		let mut resubscribing = Box::pin(stream.resubscribing_stream(backoff));
+
+		let r: std::result::Result<(), ()> = sender.notify(|| Ok("first".to_owned()));
+		r.unwrap();
+		assert_eq!(futures::executor::block_on(resubscribing.next()), Some("first".to_owned()));
+
+		// neither re-subscribing forever nor waiting for the backoff
+		sender.close_all(CloseReason::Shutdown);
+		assert_eq!(resubscribing.next().now_or_never(), Some(None));
+		assert!(stream.subscriber_labels().is_empty());
+	}
+}
+
+#[test]
+fn filter_can_be_replaced_mid_stream() {
+	let (sender, stream) = NumberStream::channel();
+	let (mut receiver, filter) = stream.subscribe_with_filter(|n| *n >= 10);
+	let notify = |n: u64| {
+		let r: std::result::Result<(), ()> = sender.notify(|| Ok(n));
+		r.unwrap();
+	};
+
+	notify(5);
+	notify(10);
+	notify(15);
+
+	filter.set_filter(|n| *n >= 20);
+
+	notify(15);
+	notify(20);
+	notify(25);
+	drop(sender);
+
+	let mut received = Vec::new();
+	while let Some(n) = receiver.next().now_or_never().flatten() {
+		received.push(n);
+	}
+	assert_eq!(received, vec![10, 15, 20, 25]);
+}
+
+#[test]
+fn cloned_senders_share_subscribers() {
+	let (first, stream) = StringStream::channel();
+	let second = first.clone();
+	let mut receiver = stream.subscribe();
+
+	let r: std::result::Result<(), ()> = first.notify(|| Ok("first".to_owned()));
+	r.unwrap();
+	let r: std::result::Result<(), ()> = second.notify(|| Ok("second".to_owned()));
+	r.unwrap();
+
+	assert_eq!(receiver.next().now_or_never(), Some(Some("first".to_owned())));
+	assert_eq!(receiver.next().now_or_never(), Some(Some("second".to_owned())));
+}
+
+#[test]
+fn sender_can_subscribe_to_its_own_notifications() {
+	let (sender, _stream) = StringStream::channel();
+	let mut receiver = sender.subscribe();
+
+	let r: std::result::Result<(), ()> = sender.notify(|| Ok("own".to_owned()));
+	r.unwrap();
+
+	assert_eq!(receiver.next().now_or_never(), Some(Some("own".to_owned())));
+}
+
+#[test]
+fn senders_created_together_feed_the_same_subscribers() {
+	let (senders, stream) = StringStream::channel_with_senders(2);
+	assert_eq!(senders.len(), 2);
+	let mut receiver = stream.subscribe();
+
+	for (i, sender) in senders.iter().enumerate() {
+		let r: std::result::Result<(), ()> = sender.notify(|| Ok(format!("sender {}", i)));
+		r.unwrap();
+	}
+
+	assert_eq!(receiver.next().now_or_never(), Some(Some("sender 0".to_owned())));
+	assert_eq!(receiver.next().now_or_never(), Some(Some("sender 1".to_owned())));
+}
+
+#[test]
+fn notify_with_ack_counts_consumed_payloads() {
+	let (sender, stream) = StringStream::channel();
+	let mut consuming = stream.subscribe();
+	let mut lagging = stream.subscribe();
+
+	let acked = sender.notify_with_ack("first".to_owned(), Duration::from_millis(50));
+	assert_eq!(consuming.next().now_or_never(), Some(Some("first".to_owned())));
+	assert_eq!(futures::executor::block_on(acked), 1);
+
+	let acked = sender.notify_with_ack("second".to_owned(), Duration::from_secs(60));
+	for receiver in [&mut consuming, &mut lagging] {
+		while let Some(Some(payload)) = receiver.next().now_or_never() {
+			if payload == "second" {
+				break
+			}
+		}
+	}
+	assert_eq!(futures::executor::block_on(acked), 2);
+}
+
+#[test]
+fn bounded_receiver_reports_its_queue() {
+	let (sender, stream) = NumberStream::channel();
+	let mut bounded = stream.subscribe_bounded(2);
+	let unbounded = stream.subscribe();
+	assert_eq!(bounded.capacity(), Some(2));
+	assert_eq!(unbounded.capacity(), None);
+
+	for n in 0..3 {
+		let r: std::result::Result<(), ()> = sender.notify(|| Ok(n));
+		r.unwrap();
+		assert_eq!(bounded.len(), (n as usize + 1).min(2));
+	}
+	assert_eq!(unbounded.len(), 3);
+
+	assert_eq!(bounded.next().now_or_never(), Some(Some(0)));
+	assert_eq!(bounded.len(), 1);
+	assert_eq!(bounded.next().now_or_never(), Some(Some(1)));
+	assert!(bounded.is_empty());
+	assert_eq!(bounded.next().now_or_never(), None);
+}
+
+#[test]
+fn full_queue_drops_newest_payloads() {
+	let (sender, stream) = NumberStream::channel();
+	let mut receiver = stream.subscribe_with_backpressure(2, Backpressure::DropNewest);
+
+	for n in 0..3 {
+		let r: std::result::Result<(), ()> = sender.notify(|| Ok(n));
+		r.unwrap();
+	}
+
+	assert_eq!(receiver.len(), 2);
+	assert_eq!(receiver.next().now_or_never(), Some(Some(0)));
+	assert_eq!(receiver.next().now_or_never(), Some(Some(1)));
+	assert_eq!(receiver.next().now_or_never(), None);
+}
+
+#[test]
+fn full_queue_drops_oldest_payloads() {
+	let (sender, stream) = NumberStream::channel();
+	let mut receiver = stream.subscribe_with_backpressure(2, Backpressure::DropOldest);
+
+	for n in 0..4 {
+		let r: std::result::Result<(), ()> = sender.notify(|| Ok(n));
+		r.unwrap();
+	}
+
+	assert_eq!(receiver.len(), 2);
+	assert_eq!(receiver.next().now_or_never(), Some(Some(2)));
+	assert_eq!(receiver.len(), 1);
+	assert_eq!(receiver.next().now_or_never(), Some(Some(3)));
+	assert!(receiver.is_empty());
+	assert_eq!(receiver.next().now_or_never(), None);
+}
+
+#[test]
+fn full_queue_blocks_the_sender_until_there_is_room() {
+	let (sender, stream) = NumberStream::channel();
+	let mut receiver = stream.subscribe_with_backpressure(1, Backpressure::Block);
+	let (sent, progress) = std::sync::mpsc::channel();
+
+	let producer = std::thread::spawn(move || {
+		for n in 0..3 {
+			let r: std::result::Result<(), ()> = sender.notify(|| Ok(n));
+			r.unwrap();
+			sent.send(n).unwrap();
+		}
+	});
+
+	assert_eq!(progress.recv().unwrap(), 0);
+	assert!(progress.recv_timeout(Duration::from_millis(50)).is_err());
+
+	assert_eq!(futures::executor::block_on(receiver.next()), Some(0));
+	assert_eq!(progress.recv().unwrap(), 1);
+	assert!(progress.recv_timeout(Duration::from_millis(50)).is_err());
+
+	// Dropping the receiver releases the blocked sender.
+	drop(receiver);
+	assert_eq!(progress.recv().unwrap(), 2);
+	producer.join().unwrap();
+}
+
+#[test]
+fn try_subscribe_fails_once_all_senders_are_gone() {
+	let (senders, stream) = StringStream::channel_with_senders(2);
+	assert!(stream.try_subscribe().is_ok());
+
+	let mut senders = senders.into_iter();
+	drop(senders.next());
+	assert!(stream.try_subscribe().is_ok());
+
+	drop(senders);
+	assert_eq!(stream.try_subscribe().err(), Some(SubscribeError::Closed));
+	assert_eq!(stream.subscribe().next().now_or_never(), Some(None));
+}
+
+#[test]
+fn channels_register_metrics_after_their_tracing_key() {
+	#[derive(Clone)]
+	struct OtherTracingKey;
+	impl TracingKeyStr for OtherTracingKey {
+		const TRACING_KEY: &'static str = "other-notification-stream";
+	}
+
+	fn dispatched(registry: &prometheus::Registry, name: &str) -> f64 {
+		let families = registry.gather();
+		let family = families
+			.iter()
+			.find(|f| f.get_name() == name)
+			.expect("the counter is registered");
+		family.get_metric()[0].get_counter().get_value()
+	}
+
+	let registry = prometheus::Registry::new();
+	let (sender, stream) = StringStream::channel_with_metrics(&registry).unwrap();
+	let (other_sender, other_stream) =
+		NotificationStream::<String, OtherTracingKey>::channel_with_metrics(&registry).unwrap();
+	let _receiver = stream.subscribe();
+	let _other_receiver = other_stream.subscribe();
+
+	for _ in 0..2 {
+		let r: std::result::Result<(), ()> = sender.notify(|| Ok("payload".to_owned()));
+		r.unwrap();
+	}
+	let r: std::result::Result<(), ()> = other_sender.notify(|| Ok("payload".to_owned()));
+	r.unwrap();
+
+	assert_eq!(dispatched(&registry, "substrate_test_notification_stream_dispatched_total"), 2.0);
+	assert_eq!(dispatched(&registry, "substrate_other_notification_stream_dispatched_total"), 1.0);
+	let names: Vec<_> = registry.gather().iter().map(|f| f.get_name().to_owned()).collect();
+	assert!(names.contains(&"substrate_other_notification_stream_subscribers".to_owned()));
+}
+
+#[test]
+fn close_all_ends_every_subscription() {
+	let (sender, stream) = StringStream::channel();
+	let mut first = stream.subscribe();
+	let mut second = stream.subscribe_bounded(1);
+
+	let r: std::result::Result<(), ()> = sender.notify(|| Ok("discarded".to_owned()));
+	r.unwrap();
+	sender.close_all(CloseReason::Shutdown);
+
+	assert_eq!(first.next().now_or_never(), Some(None));
+	assert_eq!(second.next().now_or_never(), Some(None));
+	assert!(first.is_terminated());
+
+	let r: std::result::Result<(), ()> = sender.notify(|| panic!("nobody to notify"));
+	r.unwrap();
+	assert_eq!(stream.subscribe().next().now_or_never(), Some(None));
+	assert_eq!(stream.try_subscribe().err(), Some(SubscribeError::Closed));
+}
+
+#[test]
+fn next_or_closed_tells_payloads_from_closing() {
+	let (sender, stream) = StringStream::channel();
+	let mut receiver = stream.subscribe();
+
+	let r: std::result::Result<(), ()> = sender.notify(|| Ok("payload".to_owned()));
+	r.unwrap();
+	assert_eq!(receiver.next_or_closed().now_or_never(), Some(Recv::Payload("payload".to_owned())),);
+	assert_eq!(receiver.next_or_closed().now_or_never(), None);
+
+	sender.close_all(CloseReason::Shutdown);
+	assert_eq!(receiver.next_or_closed().now_or_never(), Some(Recv::Closed));
+}
+
+#[test]
+fn high_water_mark_reflects_the_peak_queue_length() {
+	let (sender, stream) = NumberStream::channel();
+	let mut lagging = stream.subscribe_named("lagging");
+	let mut keeping_up = stream.subscribe_named("keeping up");
+
+	for n in 0..3 {
+		let r: std::result::Result<(), ()> = sender.notify(|| Ok(n));
+		r.unwrap();
+		assert_eq!(keeping_up.next().now_or_never(), Some(Some(n)));
+	}
+	while let Some(Some(_)) = lagging.next().now_or_never() {}
+
+	assert!(lagging.is_empty());
+	assert_eq!(lagging.high_water_mark(), 3);
+	assert_eq!(keeping_up.high_water_mark(), 1);
+	assert_eq!(sender.highest_water_mark(), Some(("lagging".to_owned(), 3)));
+
+	drop(lagging);
+	assert_eq!(sender.highest_water_mark(), Some(("keeping up".to_owned(), 1)));
+}
+
+#[test]
+fn subscribers_get_the_initial_then_the_latest_payload() {
+	let (sender, stream) = NumberStream::channel_with_initial(0);
+	let mut first = stream.subscribe();
+	assert_eq!(first.next().now_or_never(), Some(Some(0)));
+	assert_eq!(first.next().now_or_never(), None);
+
+	drop(first);
+	for n in 1..3 {
+		let r: std::result::Result<(), ()> = sender.notify(|| Ok(n));
+		r.unwrap();
+	}
+
+	let mut late = stream.subscribe();
+	assert_eq!(late.next().now_or_never(), Some(Some(2)));
+	assert_eq!(late.next().now_or_never(), None);
+}
+
+#[test]
+fn dropping_a_pending_next_does_not_lose_payloads() {
+	let (sender, stream) = StringStream::channel();
+	let mut receiver = stream.subscribe();
+	let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+
+	// cancelled before anything is dispatched
+	assert_eq!(receiver.next().poll_unpin(&mut cx), Poll::Pending);
+	let r: std::result::Result<(), ()> = sender.notify(|| Ok("first".to_owned()));
+	r.unwrap();
+	assert_eq!(receiver.next().now_or_never(), Some(Some("first".to_owned())));
+
+	// cancelled after being woken up by a dispatched payload, but before being polled again
+	let mut next = receiver.next_or_closed();
+	assert_eq!(next.poll_unpin(&mut cx), Poll::Pending);
+	let r: std::result::Result<(), ()> = sender.notify(|| Ok("second".to_owned()));
+	r.unwrap();
+	drop(next);
+	assert_eq!(receiver.next().now_or_never(), Some(Some("second".to_owned())));
+}
+
+#[test]
+fn peek_shows_the_next_received_payload() {
+	let (sender, stream) = NumberStream::channel();
+	let mut receiver = stream.subscribe_bounded(2);
+	assert_eq!(receiver.peek(), None);
+
+	for n in 0..2 {
+		let r: std::result::Result<(), ()> = sender.notify(|| Ok(n));
+		r.unwrap();
+	}
+
+	assert_eq!(receiver.peek(), Some(&0));
+	assert_eq!(receiver.peek(), Some(&0));
+	assert_eq!(receiver.len(), 2);
+	assert_eq!(receiver.next().now_or_never(), Some(Some(0)));
+	assert_eq!(receiver.peek(), Some(&1));
+	assert_eq!(receiver.next().now_or_never(), Some(Some(1)));
+	assert_eq!(receiver.peek(), None);
+	assert!(receiver.is_empty());
+}
+
+#[test]
+fn merged_receivers_interleave_until_both_end() {
+	use futures::future::Either;
+
+	let (numbers, number_stream) = NumberStream::channel();
+	let (strings, string_stream) = StringStream::channel();
+	let mut merged = number_stream.subscribe().merge(string_stream.subscribe());
+
+	let r: std::result::Result<(), ()> = numbers.notify(|| Ok(1));
+	r.unwrap();
+	assert!(matches!(merged.next().now_or_never(), Some(Some(Either::Left(1)))));
+	let r: std::result::Result<(), ()> = strings.notify(|| Ok("one".to_owned()));
+	r.unwrap();
+	assert!(matches!(merged.next().now_or_never(), Some(Some(Either::Right(s))) if s == "one"));
+	assert_eq!(number_stream.subscriber_labels().len(), 1);
+	assert_eq!(string_stream.subscriber_labels().len(), 1);
+
+	numbers.close_all(CloseReason::Shutdown);
+	let r: std::result::Result<(), ()> = strings.notify(|| Ok("two".to_owned()));
+	r.unwrap();
+	assert!(matches!(merged.next().now_or_never(), Some(Some(Either::Right(s))) if s == "two"));
+	assert!(merged.next().now_or_never().is_none());
+
+	strings.close_all(CloseReason::Shutdown);
+	assert!(matches!(merged.next().now_or_never(), Some(None)));
+}
+
+#[test]
+fn batching_sender_batches_by_size_by_time_and_on_drop() {
+	let mut pool = futures::executor::LocalPool::new();
+	let (sender, stream) = NotificationStream::<Vec<u64>, DummyTracingKey>::channel();
+	let mut receiver = stream.subscribe();
+	let batching =
+		BatchingSender::new(sender, 2, Duration::from_millis(20), &pool.spawner()).unwrap();
+
+	for n in 0..3 {
+		batching.notify(n);
+	}
+	assert_eq!(receiver.next().now_or_never(), Some(Some(vec![0, 1])));
+	assert_eq!(receiver.next().now_or_never(), None);
+
+	assert_eq!(pool.run_until(receiver.next()), Some(vec![2]));
+
+	batching.notify(3);
+	drop(batching);
+	assert_eq!(receiver.next().now_or_never(), Some(Some(vec![3])));
+	// the flushing task ends along with the sender
+	pool.run();
+}
+
+#[test]
+fn batches_flushed_concurrently_are_dispatched_in_order() {
+	let pool = futures::executor::LocalPool::new();
+	let (sender, stream) = NotificationStream::<Vec<u64>, DummyTracingKey>::channel();
+	let receiver = stream.subscribe();
+	let batching = Arc::new(
+		BatchingSender::new(sender, 2, Duration::from_secs(3600), &pool.spawner()).unwrap(),
+	);
+
+	// flushing periodically while the batches fill up
+	let flushing: Vec<_> = (0..4)
+		.map(|_| {
+			let batching = batching.clone();
+			std::thread::spawn(move || {
+				for _ in 0..20_000 {
+					batching.flush();
+				}
+			})
+		})
+		.collect();
+	for n in 0..100_000 {
+		batching.notify(n);
+	}
+	for flushing in flushing {
+		flushing.join().unwrap();
+	}
+	drop(batching);
+
+	let received: Vec<u64> = futures::executor::block_on(receiver.concat());
+	assert_eq!(received, (0..100_000).collect::<Vec<_>>());
+}
+
+#[test]
+fn subscribers_report_their_diagnostics() {
+	let before = std::time::Instant::now();
+	let (sender, stream) = NumberStream::channel();
+	let _gadget = stream.subscribe_named("gadget");
+	let mut rpc = stream.subscribe_named("rpc");
+	let _unnamed = stream.subscribe();
+
+	for n in 0..2 {
+		let r: std::result::Result<(), ()> = sender.notify(|| Ok(n));
+		r.unwrap();
+	}
+	assert_eq!(rpc.next().now_or_never(), Some(Some(0)));
+
+	let infos: Vec<_> = stream.subscribers().collect();
+	let names: Vec<_> = infos.iter().map(|i| i.name.as_deref()).collect();
+	assert_eq!(names, vec![Some("gadget"), Some("rpc"), None]);
+	let queue_lens: Vec<_> = infos.iter().map(|i| i.queue_len).collect();
+	assert_eq!(queue_lens, vec![2, 1, 2]);
+	assert!(infos.iter().all(|i| i.subscribed_at >= before));
+	assert!(infos[0].subscribed_at <= infos[1].subscribed_at);
+}
+
+#[test]
+fn multiplexed_stream_routes_payloads_by_kind() {
+	#[derive(Clone, Debug, PartialEq)]
+	enum Event {
+		Imported(u64),
+		Finalized(u64),
+		Pruned(u64),
+	}
+
+	#[derive(Clone, Copy, PartialEq, Eq)]
+	enum EventKind {
+		Imported,
+		Finalized,
+		Pruned,
+	}
+
+	impl MultiplexedPayload for Event {
+		type Kind = EventKind;
+
+		fn kind(&self) -> EventKind {
+			match self {
+				Event::Imported(_) => EventKind::Imported,
+				Event::Finalized(_) => EventKind::Finalized,
+				Event::Pruned(_) => EventKind::Pruned,
+			}
+		}
+	}
+
+	let (sender, stream) = MultiplexedStream::<Event, DummyTracingKey>::channel();
+	let mut imports = stream.subscribe([EventKind::Imported]);
+	let mut finality = stream.subscribe([EventKind::Finalized, EventKind::Pruned]);
+	let mut all = stream.subscribe_all();
+
+	let events = [Event::Imported(1), Event::Finalized(1), Event::Pruned(0), Event::Imported(2)];
+	for event in events.iter().cloned() {
+		let r: std::result::Result<(), ()> = sender.notify(|| Ok(event));
+		r.unwrap();
+	}
+
+	let received = |receiver: &mut NotificationReceiver<Event>| {
+		let mut received = Vec::new();
+		while let Some(Some(event)) = receiver.next().now_or_never() {
+			received.push(event);
+		}
+		received
+	};
+	assert_eq!(received(&mut imports), vec![Event::Imported(1), Event::Imported(2)]);
+	assert_eq!(received(&mut finality), vec![Event::Finalized(1), Event::Pruned(0)]);
+	assert_eq!(received(&mut all), events.to_vec());
+}
+
+#[test]
+fn higher_priority_subscribers_get_payloads_first() {
+	let (sender, stream) = NumberStream::channel();
+	let mut blocking = stream.subscribe_with_backpressure(1, Backpressure::Block);
+	let mut prioritized = stream.subscribe_with_priority(1);
+	let r: std::result::Result<(), ()> = sender.notify(|| Ok(0));
+	r.unwrap();
+	assert_eq!(prioritized.next().now_or_never(), Some(Some(0)));
+
+	// blocks on the full queue of the lower priority subscriber
+	let producer = std::thread::spawn(move || {
+		let r: std::result::Result<(), ()> = sender.notify(|| Ok(1));
+		r.unwrap();
+	});
+
+	assert_eq!(futures::executor::block_on(prioritized.next()), Some(1));
+	assert!(!producer.is_finished());
+	assert_eq!(futures::executor::block_on(blocking.next()), Some(0));
+	producer.join().unwrap();
+	assert_eq!(blocking.next().now_or_never(), Some(Some(1)));
+}
+
+#[test]
+fn receivers_learn_why_the_channel_closed() {
+	let (sender, stream) = StringStream::channel();
+	let mut receiver = stream.subscribe();
+	assert_eq!(receiver.close_reason(), None);
+
+	sender.close_all(CloseReason::Failure("database gone".to_owned()));
+	assert_eq!(receiver.next().now_or_never(), Some(None));
+	assert_eq!(receiver.close_reason(), Some(CloseReason::Failure("database gone".to_owned())));
+
+	let late = stream.subscribe();
+	assert_eq!(late.close_reason(), Some(CloseReason::Failure("database gone".to_owned())));
+}
+
+#[test]
+fn forwarded_streams_are_dispatched_until_they_end() {
+	let mut pool = futures::executor::LocalPool::new();
+	let (sender, stream) = NumberStream::channel();
+	let mut receiver = stream.subscribe();
+
+	let _forward = sender.spawn_forward(futures::stream::iter(0..3), &pool.spawner()).unwrap();
+	// only returns once the forwarding task is over
+	pool.run();
+	for n in 0..3 {
+		assert_eq!(receiver.next().now_or_never(), Some(Some(n)));
+	}
+
+	// nothing is forwarded anymore once the handle is dropped
+	let (source, items) = futures::channel::mpsc::unbounded();
+	let forward = sender.spawn_forward(items, &pool.spawner()).unwrap();
+	source.unbounded_send(3).unwrap();
+	pool.run_until_stalled();
+	assert_eq!(receiver.next().now_or_never(), Some(Some(3)));
+	drop(forward);
+	source.unbounded_send(4).unwrap();
+	pool.run();
+	assert_eq!(receiver.next().now_or_never(), None);
+	assert!(source.is_closed());
+}